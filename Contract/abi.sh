@@ -0,0 +1,5 @@
+#!/bin/bash
+
+# Requires cargo-near: cargo install cargo-near
+# Writes the ABI to target/near/curb_abi.json
+cargo near abi
\ No newline at end of file
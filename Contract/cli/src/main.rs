@@ -0,0 +1,884 @@
+//! `curb` administers a Curb chat instance over JSON-RPC, against any network including a local
+//! sandbox.
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::{Args, Parser, Subcommand};
+use near_crypto::{InMemorySigner, SecretKey};
+use near_sdk::AccountId;
+use serde::{Deserialize, Serialize};
+
+use curb::{
+    Attachment, Channel, MessageFormat, MessageId, MessageOptions, MessageWithReactionsAndThread,
+    Poll, Presence, ReceivedMessage, RetentionPolicy,
+};
+use curb_client::CurbClient;
+
+type CliResult<T> = Result<T, Box<dyn Error>>;
+
+/// Messages fetched per `get_messages_received_after` call by `tail`.
+const TAIL_PAGE: usize = 100;
+
+#[derive(Parser)]
+#[command(name = "curb", about = "Administer a Curb chat instance")]
+struct Cli {
+    /// JSON-RPC endpoint of the network the contract is deployed on.
+    #[arg(long, env = "CURB_RPC_URL", default_value = "http://localhost:3030")]
+    rpc_url: String,
+    /// Account the Curb contract is deployed to.
+    #[arg(long, env = "CURB_CONTRACT")]
+    contract: AccountId,
+    /// Key file of the calling account, as written by near-cli or the sandbox.
+    #[arg(long, env = "CURB_CREDENTIALS")]
+    credentials: Option<PathBuf>,
+    /// Print results as JSON.
+    #[arg(long)]
+    json: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Deploy the contract wasm to the contract account and initialize it.
+    Deploy {
+        wasm: PathBuf,
+        #[arg(long)]
+        name: String,
+        /// Account allowed to configure and moderate the instance.
+        #[arg(long)]
+        owner: AccountId,
+    },
+    /// Join the instance, with an invite code when registration requires one.
+    Join {
+        #[arg(long)]
+        invite: Option<String>,
+    },
+    /// Create, list and manage channels.
+    #[command(subcommand)]
+    Channels(ChannelsCommand),
+    /// Send a message to a channel or DM.
+    Send {
+        #[command(flatten)]
+        target: Target,
+        message: String,
+        /// Reply in the thread of this message.
+        #[arg(long)]
+        thread: Option<String>,
+        /// Quote this message of the same channel or DM.
+        #[arg(long)]
+        reply_to: Option<String>,
+        /// Forward this message from any channel or DM visible to the caller.
+        #[arg(long)]
+        forward: Option<String>,
+        /// Attachment metadata as JSON, e.g. `{"contentHash": "..", "mimeType": "image/png",
+        /// "size": 1024, "filename": "cat.png", "uri": "ipfs://.."}`. May be repeated.
+        #[arg(long = "attachment", value_parser = parse_attachment)]
+        attachments: Vec<Attachment>,
+        /// Render the message as markdown.
+        #[arg(long, conflicts_with = "code")]
+        markdown: bool,
+        /// Render the message as a code block, highlighted as the given language.
+        #[arg(long)]
+        code: Option<Option<String>>,
+        /// Make the message disappear after this many seconds.
+        #[arg(long)]
+        expires_in: Option<u64>,
+    },
+    /// Start a poll in a channel.
+    Poll {
+        #[arg(long)]
+        group: String,
+        question: String,
+        #[arg(required = true, num_args = 2..)]
+        options: Vec<String>,
+        /// Let members vote for several options.
+        #[arg(long)]
+        multiple: bool,
+        /// Close the poll after this many seconds.
+        #[arg(long)]
+        duration: Option<u64>,
+    },
+    /// Vote for options of a poll by index, or withdraw the vote when none are given.
+    Vote {
+        message_id: String,
+        options: Vec<u8>,
+    },
+    /// Make new messages of a channel or DM disappear after `--seconds`, or turn that off.
+    Expiry {
+        #[command(flatten)]
+        target: Target,
+        #[arg(long)]
+        seconds: Option<u64>,
+    },
+    /// Remove expired messages of a channel or DM, refunding their senders.
+    RemoveExpired {
+        #[command(flatten)]
+        target: Target,
+        #[arg(long)]
+        limit: Option<u32>,
+    },
+    /// Print messages of a channel or DM.
+    Messages {
+        #[command(flatten)]
+        target: Target,
+        #[arg(long)]
+        offset: Option<usize>,
+        #[arg(long)]
+        length: Option<usize>,
+    },
+    /// Print the latest messages of a channel tagged with a hashtag, when the instance indexes
+    /// tags.
+    Tag {
+        #[arg(long)]
+        group: String,
+        tag: String,
+        /// `next` cursor printed with the previous page.
+        #[arg(long)]
+        before: Option<u64>,
+        #[arg(long)]
+        length: Option<usize>,
+    },
+    /// Follow new messages of a channel or DM.
+    Tail {
+        #[command(flatten)]
+        target: Target,
+        /// Polling interval in seconds.
+        #[arg(long, default_value_t = 2)]
+        interval: u64,
+    },
+    /// Set the presence others see, `online`, `away`, `dnd` or `offline`, with an optional
+    /// status text. `online` without a text clears the status.
+    Status {
+        #[arg(value_parser = parse_presence)]
+        presence: Presence,
+        #[arg(long)]
+        text: Option<String>,
+        /// Clear the status after this many seconds.
+        #[arg(long = "for")]
+        duration: Option<u64>,
+    },
+    /// List members of the instance or of a channel with their presence.
+    Members {
+        #[arg(long)]
+        group: Option<String>,
+    },
+    /// Print unread message counts, for the calling account by default.
+    Unread { account: Option<AccountId> },
+    /// Kick, mute and ban members and inspect the moderation log.
+    #[command(subcommand)]
+    Moderation(ModerationCommand),
+}
+
+#[derive(Subcommand)]
+enum ChannelsCommand {
+    /// List all channels, or those `--account` is a member of.
+    List {
+        #[arg(long)]
+        account: Option<AccountId>,
+    },
+    Create {
+        name: String,
+    },
+    Join {
+        name: String,
+    },
+    Leave {
+        name: String,
+    },
+    Invite {
+        name: String,
+        account: AccountId,
+    },
+    Info {
+        name: String,
+    },
+    /// Limit the history a channel keeps, applied by `prune`.
+    Retention {
+        name: String,
+        #[arg(long)]
+        max_messages: Option<u32>,
+        /// Maximum message age in days.
+        #[arg(long)]
+        max_age_days: Option<u64>,
+    },
+    /// Remove messages outside the channel's retention policy.
+    Prune {
+        name: String,
+        #[arg(long)]
+        max_items: Option<u32>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ModerationCommand {
+    Kick {
+        group: String,
+        account: AccountId,
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    Mute {
+        group: String,
+        account: AccountId,
+        /// Mute duration in seconds.
+        #[arg(long)]
+        duration: u64,
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    Unmute {
+        group: String,
+        account: AccountId,
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    Ban {
+        group: String,
+        account: AccountId,
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    Unban {
+        group: String,
+        account: AccountId,
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Ban an account from the whole instance.
+    BanMember {
+        account: AccountId,
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    UnbanMember {
+        account: AccountId,
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Print the moderation log, of a single channel with `--group`.
+    Log {
+        #[arg(long)]
+        group: Option<String>,
+        #[arg(long)]
+        offset: Option<usize>,
+        #[arg(long)]
+        length: Option<usize>,
+    },
+}
+
+#[derive(Args)]
+#[group(required = true, multiple = false)]
+struct Target {
+    /// Channel name.
+    #[arg(long)]
+    group: Option<String>,
+    /// Account of the DM partner.
+    #[arg(long)]
+    to: Option<AccountId>,
+}
+
+/// Key file written by near-cli (`private_key`) or the sandbox (`secret_key`).
+#[derive(Deserialize)]
+struct KeyFile {
+    account_id: String,
+    #[serde(alias = "private_key")]
+    secret_key: SecretKey,
+}
+
+fn load_signer(path: &Path) -> CliResult<InMemorySigner> {
+    let key_file: KeyFile = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    Ok(InMemorySigner::from_secret_key(
+        key_file.account_id.parse()?,
+        key_file.secret_key,
+    ))
+}
+
+fn channel(name: String) -> Channel {
+    Channel { name }
+}
+
+fn parse_attachment(json: &str) -> Result<Attachment, serde_json::Error> {
+    serde_json::from_str(json)
+}
+
+fn parse_presence(presence: &str) -> Result<Presence, String> {
+    match presence {
+        "online" => Ok(Presence::Online),
+        "away" => Ok(Presence::Away),
+        "dnd" => Ok(Presence::DoNotDisturb),
+        "offline" => Ok(Presence::Offline),
+        _ => Err("expected online, away, dnd or offline".to_string()),
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// Newest messages `tail` has printed of a DM, by the block time they were received at. Unlike a
+/// count of messages this is not shifted by messages sent with an earlier client timestamp or
+/// removed by expiry and pruning. Channels are followed with `get_messages_received_after`.
+#[derive(Default)]
+struct TailCursor {
+    received_at: u64,
+    /// Messages received at `received_at`, as several can land in the same block.
+    ids: HashSet<MessageId>,
+}
+
+impl TailCursor {
+    /// Keeps the messages received after the cursor and moves it past them.
+    fn advance(
+        &mut self,
+        messages: Vec<MessageWithReactionsAndThread>,
+    ) -> Vec<MessageWithReactionsAndThread> {
+        let messages: Vec<_> = messages
+            .into_iter()
+            .filter(|m| {
+                m.received_at > self.received_at
+                    || (m.received_at == self.received_at && !self.ids.contains(&m.id))
+            })
+            .collect();
+        if let Some(newest) = messages.iter().map(|m| m.received_at).max() {
+            if newest > self.received_at {
+                self.received_at = newest;
+                self.ids.clear();
+            }
+            self.ids.extend(
+                messages
+                    .iter()
+                    .filter(|m| m.received_at == newest)
+                    .map(|m| m.id.clone()),
+            );
+        }
+        messages
+    }
+}
+
+struct Context {
+    client: CurbClient,
+    json: bool,
+}
+
+impl Context {
+    fn print<T: Serialize>(&self, value: &T, human: impl FnOnce(&T)) -> CliResult<()> {
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(value)?);
+        } else {
+            human(value);
+        }
+        Ok(())
+    }
+
+    fn account_id(&self) -> CliResult<AccountId> {
+        self.client
+            .account_id()
+            .ok_or_else(|| "--credentials are required for this command".into())
+    }
+
+    async fn messages(
+        &self,
+        target: &Target,
+        offset: Option<usize>,
+        length: Option<usize>,
+    ) -> CliResult<Vec<MessageWithReactionsAndThread>> {
+        let accounts = match &target.to {
+            Some(other) => Some((self.account_id()?, other.clone())),
+            None => None,
+        };
+        Ok(self
+            .client
+            .get_messages(
+                accounts,
+                target.group.clone().map(channel),
+                offset,
+                length,
+                self.client.account_id(),
+            )
+            .await?)
+    }
+
+    /// Messages of `group`, top-level and thread replies, received after `cursor`, which is moved
+    /// past them.
+    async fn received_after(
+        &self,
+        group: &Channel,
+        cursor: &mut Option<(u64, MessageId)>,
+    ) -> CliResult<Vec<ReceivedMessage>> {
+        let mut messages = vec![];
+        loop {
+            let page = self
+                .client
+                .get_messages_received_after(group.clone(), cursor.clone(), Some(TAIL_PAGE))
+                .await?;
+            let fetched = page.len();
+            if let Some(last) = page.last() {
+                *cursor = Some((last.message.received_at, last.message.id.clone()));
+            }
+            messages.extend(page);
+            if fetched < TAIL_PAGE {
+                return Ok(messages);
+            }
+        }
+    }
+
+    fn print_received(&self, messages: &[ReceivedMessage]) -> CliResult<()> {
+        for received in messages {
+            if self.json {
+                println!("{}", serde_json::to_string(received)?);
+                continue;
+            }
+            let message = &received.message;
+            if let Some(parent) = &received.parent {
+                println!("  in thread {}", parent);
+            }
+            println!(
+                "[{}] {} {}: {}",
+                message.timestamp, message.id, message.sender, message.text
+            );
+        }
+        Ok(())
+    }
+
+    fn print_messages(&self, messages: &[MessageWithReactionsAndThread]) -> CliResult<()> {
+        if self.json {
+            for message in messages {
+                println!("{}", serde_json::to_string(message)?);
+            }
+            return Ok(());
+        }
+        for message in messages {
+            for (label, quote) in [
+                ("replying to", &message.reply_to),
+                ("forwarded from", &message.forwarded_from),
+            ] {
+                if let Some(quote) = quote {
+                    println!("  {} {}: {}", label, quote.sender, quote.text);
+                }
+            }
+            println!(
+                "[{}] {} {}: {}",
+                message.timestamp, message.id, message.sender, message.text
+            );
+            if let Some(poll) = &message.poll {
+                let state = if poll.closed { "closed" } else { "open" };
+                println!("  poll ({}, {} voters)", state, poll.voters);
+                for (i, (option, votes)) in poll.options.iter().zip(poll.votes.iter()).enumerate() {
+                    let mine = if poll.my_votes.contains(&(i as u8)) {
+                        "*"
+                    } else {
+                        " "
+                    };
+                    println!("  {}{} {}\t{}", mine, i, option, votes);
+                }
+            }
+            for attachment in message.attachments.iter() {
+                println!(
+                    "  [{}, {} bytes] {} {}",
+                    attachment.mime_type, attachment.size, attachment.filename, attachment.uri
+                );
+            }
+            for reply in message.thread.iter() {
+                println!(
+                    "    [{}] {} {}: {}",
+                    reply.timestamp, reply.id, reply.sender, reply.text
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn run(cli: Cli) -> CliResult<()> {
+    let mut client = CurbClient::new(&cli.rpc_url, cli.contract);
+    if let Some(path) = cli.credentials.as_deref() {
+        client = client.with_signer(load_signer(path)?);
+    }
+    let ctx = Context {
+        client,
+        json: cli.json,
+    };
+    let client = &ctx.client;
+
+    match cli.command {
+        Command::Deploy { wasm, name, owner } => {
+            client.deploy(std::fs::read(wasm)?, name, owner).await?;
+        }
+        Command::Join { invite } => {
+            let group = match invite {
+                Some(code) => client.join_with_invite(code).await?,
+                None => client.join().await?,
+            };
+            ctx.print(&group, |group| println!("joined {}", group.name))?;
+        }
+        Command::Channels(command) => match command {
+            ChannelsCommand::List { account } => {
+                let groups = client.get_groups(account).await?;
+                ctx.print(&groups, |groups| {
+                    for group in groups {
+                        println!("{}", group.name);
+                    }
+                })?;
+            }
+            ChannelsCommand::Create { name } => {
+                let group = client.create_group(channel(name)).await?;
+                ctx.print(&group, |group| println!("created {}", group.name))?;
+            }
+            ChannelsCommand::Join { name } => {
+                client.join_group(channel(name)).await?;
+            }
+            ChannelsCommand::Leave { name } => {
+                client.leave_group(channel(name)).await?;
+            }
+            ChannelsCommand::Invite { name, account } => {
+                client.group_invite(channel(name), account).await?;
+            }
+            ChannelsCommand::Info { name } => {
+                let info = client.channel_info(channel(name)).await?;
+                ctx.print(&info, |info| match info {
+                    Some(info) => println!("created by {} at {}", info.created_by, info.created_at),
+                    None => println!("no such channel"),
+                })?;
+            }
+            ChannelsCommand::Retention {
+                name,
+                max_messages,
+                max_age_days,
+            } => {
+                let retention = RetentionPolicy {
+                    max_messages,
+                    max_age_ms: max_age_days.map(|days| days * 24 * 60 * 60 * 1000),
+                };
+                client.set_retention(channel(name), retention).await?;
+            }
+            ChannelsCommand::Prune { name, max_items } => {
+                let removed = client.prune(channel(name), max_items).await?;
+                ctx.print(&removed, |removed| println!("removed {} messages", removed))?;
+            }
+        },
+        Command::Send {
+            target,
+            message,
+            thread,
+            reply_to,
+            forward,
+            attachments,
+            markdown,
+            code,
+            expires_in,
+        } => {
+            let now = now_ms();
+            let format = match code {
+                Some(language) => MessageFormat::Code { language },
+                None if markdown => MessageFormat::Markdown,
+                None => MessageFormat::Plain,
+            };
+            let options = MessageOptions {
+                reply_to,
+                forwarded_from: forward,
+                attachments,
+                format,
+                expires_at: expires_in.map(|seconds| now + seconds * 1000),
+                ..Default::default()
+            };
+            client
+                .send_message(
+                    target.to,
+                    target.group.map(channel),
+                    message,
+                    now,
+                    thread,
+                    Some(options),
+                )
+                .await?;
+        }
+        Command::Poll {
+            group,
+            question,
+            options,
+            multiple,
+            duration,
+        } => {
+            let now = now_ms();
+            let poll = Poll {
+                question: question.clone(),
+                options,
+                multiple_choice: multiple,
+                deadline: duration.map(|seconds| now + seconds * 1000),
+            };
+            client
+                .send_message(
+                    None,
+                    Some(channel(group)),
+                    question,
+                    now,
+                    None,
+                    Some(MessageOptions {
+                        poll: Some(poll),
+                        ..Default::default()
+                    }),
+                )
+                .await?;
+        }
+        Command::Expiry { target, seconds } => {
+            client
+                .set_message_expiry(
+                    target.to,
+                    target.group.map(channel),
+                    seconds.map(|seconds| seconds * 1000),
+                )
+                .await?;
+        }
+        Command::RemoveExpired { target, limit } => {
+            let accounts = match target.to {
+                Some(other) => Some((ctx.account_id()?, other)),
+                None => None,
+            };
+            let removed = client
+                .remove_expired_messages(accounts, target.group.map(channel), limit)
+                .await?;
+            ctx.print(&removed, |removed| println!("removed {} messages", removed))?;
+        }
+        Command::Vote {
+            message_id,
+            options,
+        } => client.vote(message_id, options).await?,
+        Command::Messages {
+            target,
+            offset,
+            length,
+        } => {
+            let messages = ctx.messages(&target, offset, length).await?;
+            ctx.print_messages(&messages)?;
+        }
+        Command::Tag {
+            group,
+            tag,
+            before,
+            length,
+        } => {
+            let page = client
+                .get_messages_by_tag(channel(group), tag, before, length, client.account_id())
+                .await?;
+            ctx.print(&page, |page| {
+                for message in page.messages.iter() {
+                    println!(
+                        "[{}] {} {}: {}",
+                        message.timestamp, message.id, message.sender, message.text
+                    );
+                }
+                if let Some(next) = page.next {
+                    println!("more with --before {}", next);
+                }
+            })?;
+        }
+        Command::Tail { target, interval } => match target.group.clone() {
+            Some(name) => {
+                let group = channel(name);
+                let mut cursor = None;
+                ctx.received_after(&group, &mut cursor).await?;
+                loop {
+                    tokio::time::sleep(Duration::from_secs(interval)).await;
+                    let messages = ctx.received_after(&group, &mut cursor).await?;
+                    ctx.print_received(&messages)?;
+                }
+            }
+            None => {
+                let mut cursor = TailCursor::default();
+                cursor.advance(ctx.messages(&target, None, None).await?);
+                loop {
+                    tokio::time::sleep(Duration::from_secs(interval)).await;
+                    let messages = cursor.advance(ctx.messages(&target, None, None).await?);
+                    ctx.print_messages(&messages)?;
+                }
+            }
+        },
+        Command::Status {
+            presence,
+            text,
+            duration,
+        } => {
+            client
+                .set_status(
+                    presence,
+                    text,
+                    duration.map(|seconds| now_ms() + seconds * 1000),
+                )
+                .await?;
+        }
+        Command::Members { group } => {
+            let members = client.get_members(group.map(channel)).await?;
+            ctx.print(&members, |members| {
+                for member in members {
+                    println!(
+                        "{}\t{:?}\t{}\t{}",
+                        member.id,
+                        member.presence,
+                        member.last_seen,
+                        member.status_text.as_deref().unwrap_or_default()
+                    );
+                }
+            })?;
+        }
+        Command::Unread { account } => {
+            let account = match account {
+                Some(account) => account,
+                None => ctx.account_id()?,
+            };
+            let unread = client.unread_messages(account).await?;
+            ctx.print(&unread, |unread| {
+                for (name, info) in unread.channels.iter() {
+                    println!("#{}\t{}", name, info.count);
+                }
+                for (account, info) in unread.chats.iter() {
+                    println!("@{}\t{}", account, info.count);
+                }
+            })?;
+        }
+        Command::Moderation(command) => match command {
+            ModerationCommand::Kick {
+                group,
+                account,
+                reason,
+            } => {
+                client.kick(channel(group), account, reason).await?;
+            }
+            ModerationCommand::Mute {
+                group,
+                account,
+                duration,
+                reason,
+            } => {
+                client
+                    .mute(channel(group), account, duration * 1000, reason)
+                    .await?;
+            }
+            ModerationCommand::Unmute {
+                group,
+                account,
+                reason,
+            } => {
+                client.unmute(channel(group), account, reason).await?;
+            }
+            ModerationCommand::Ban {
+                group,
+                account,
+                reason,
+            } => {
+                client.ban(channel(group), account, reason).await?;
+            }
+            ModerationCommand::Unban {
+                group,
+                account,
+                reason,
+            } => {
+                client.unban(channel(group), account, reason).await?;
+            }
+            ModerationCommand::BanMember { account, reason } => {
+                client.ban_member(account, reason).await?
+            }
+            ModerationCommand::UnbanMember { account, reason } => {
+                client.unban_member(account, reason).await?
+            }
+            ModerationCommand::Log {
+                group,
+                offset,
+                length,
+            } => {
+                let log = client
+                    .get_moderation_log(group.map(channel), offset, length)
+                    .await?;
+                ctx.print(&log, |log| {
+                    for entry in log {
+                        println!(
+                            "[{}] {} {:?} {}{}{}",
+                            entry.timestamp,
+                            entry.moderator,
+                            entry.action,
+                            entry.account,
+                            entry
+                                .group
+                                .as_ref()
+                                .map(|g| format!(" in #{}", g.name))
+                                .unwrap_or_default(),
+                            entry
+                                .reason
+                                .as_ref()
+                                .map(|r| format!(": {}", r))
+                                .unwrap_or_default(),
+                        );
+                    }
+                })?;
+            }
+        },
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run(Cli::parse()).await {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: &str, received_at: u64) -> MessageWithReactionsAndThread {
+        MessageWithReactionsAndThread {
+            id: id.to_string(),
+            text: id.to_string(),
+            timestamp: 0,
+            sender: "alice.near".parse().unwrap(),
+            deleted: false,
+            expires_at: None,
+            received_at,
+            attachments: vec![],
+            format: MessageFormat::default(),
+            link_previews: vec![],
+            poll: None,
+            reactions: vec![],
+            reply_to: None,
+            forwarded_from: None,
+            thread: vec![],
+        }
+    }
+
+    fn ids(messages: Vec<MessageWithReactionsAndThread>) -> Vec<MessageId> {
+        messages.into_iter().map(|m| m.id).collect()
+    }
+
+    #[test]
+    fn tail_cursor_skips_seen_messages_received_in_the_same_block() {
+        let mut cursor = TailCursor::default();
+        assert_eq!(
+            ids(cursor.advance(vec![message("a", 10), message("b", 20)])),
+            ["a", "b"]
+        );
+        // `c` landed in the same block as `b` after the previous poll.
+        assert_eq!(
+            ids(cursor.advance(vec![message("a", 10), message("b", 20), message("c", 20)])),
+            ["c"]
+        );
+        assert!(cursor
+            .advance(vec![message("b", 20), message("c", 20)])
+            .is_empty());
+        // A later block resets the seen ids, a message sent with an earlier client timestamp is
+        // still listed by receive time.
+        assert_eq!(
+            ids(cursor.advance(vec![message("d", 30), message("c", 20), message("e", 30)])),
+            ["d", "e"]
+        );
+        assert_eq!(cursor.ids.len(), 2);
+        assert!(cursor.advance(vec![message("e", 30)]).is_empty());
+    }
+}
@@ -0,0 +1,66 @@
+use curb::ErrorInfo;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The contract rejected the call with one of its `CurbError` codes.
+    #[error("{}", .0.message)]
+    Contract(ErrorInfo),
+    /// The call failed for a reason other than a `CurbError`, e.g. running out of gas.
+    #[error("execution failed: {0}")]
+    Execution(String),
+    #[error("rpc error: {0}")]
+    Rpc(String),
+    #[error("invalid account id: {0}")]
+    AccountId(String),
+    #[error("a signer is required to send transactions")]
+    MissingSigner,
+    #[error("unexpected response: {0}")]
+    UnexpectedResponse(String),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+impl Error {
+    /// Classifies an execution failure message, extracting the `CurbError` the contract panicked
+    /// with when there is one.
+    pub(crate) fn from_failure(message: String) -> Self {
+        match Error::parse_contract_error(&message) {
+            Some(info) => Error::Contract(info),
+            None => Error::Execution(message),
+        }
+    }
+
+    fn parse_contract_error(message: &str) -> Option<ErrorInfo> {
+        // Failures are often reported through `Debug`, which escapes the quotes of the payload.
+        let message = message.replace("\\\"", "\"");
+        let start = message.find("{\"code\"")?;
+        let end = message[start..].find('}')? + start + 1;
+        serde_json::from_str(&message[start..end]).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use curb::CurbError;
+
+    #[test]
+    fn extracts_contract_error_from_panic_message() {
+        let message = r#"Action #0: ExecutionError("Smart contract panicked: {\"code\":\"NOT_A_MEMBER\",\"message\":\"Not a member\"}")"#
+            .to_string();
+        match Error::from_failure(message) {
+            Error::Contract(info) => assert_eq!(info, CurbError::NotAMember.info()),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn keeps_other_failures_as_execution_errors() {
+        assert!(matches!(
+            Error::from_failure("Exceeded the prepaid gas.".to_string()),
+            Error::Execution(_)
+        ));
+    }
+}
@@ -0,0 +1,714 @@
+//! Typed client for the `Curb` chat contract.
+//!
+//! Every contract method has an async counterpart taking and returning the contract's own types,
+//! so bots, scripts and tests share one interface. The client talks JSON-RPC to any NEAR node,
+//! including a local sandbox:
+//!
+//! ```no_run
+//! # async fn run() -> curb_client::Result<()> {
+//! use curb::Channel;
+//! use curb_client::CurbClient;
+//!
+//! let signer = near_crypto::InMemorySigner::from_secret_key(
+//!     "alice.test.near".parse().unwrap(),
+//!     "ed25519:...".parse().unwrap(),
+//! );
+//! let client = CurbClient::new("http://localhost:3030", "curb.test.near".parse().unwrap())
+//!     .with_signer(signer);
+//! client.join().await?;
+//! let general = Channel { name: "general".to_string() };
+//! client
+//!     .send_message(None, Some(general.clone()), "hello".to_string(), 0, None, None)
+//!     .await?;
+//! let messages = client.get_messages(None, Some(general), None, None, None).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+
+use near_crypto::InMemorySigner;
+use near_jsonrpc_client::{methods, JsonRpcClient};
+use near_jsonrpc_primitives::types::query::QueryResponseKind;
+use near_primitives::hash::CryptoHash;
+use near_primitives::transaction::{Action, DeployContractAction, FunctionCallAction, Transaction};
+use near_primitives::types::{Balance, BlockReference, Gas};
+use near_primitives::views::{FinalExecutionStatus, QueryRequest};
+use near_sdk::{AccountId, PublicKey};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+use curb::{
+    Channel, ChannelMetadata, Config, ErrorInfo, MessageId, MessageOptions,
+    MessageWithReactionsAndThread, ModerationEntry, PollResults, Presence, RateLimitBudget,
+    ReceivedMessage, RetentionPolicy, TaggedMessages, UnreadMessageInfo, UserInfo,
+};
+
+mod error;
+
+pub use error::{Error, Result};
+pub use near_primitives::types::Finality;
+
+/// Gas attached to every call unless overridden with [`CurbClient::with_gas`].
+pub const DEFAULT_GAS: Gas = 100_000_000_000_000;
+
+/// Hash `create_invite` takes for an invite that only `account` can redeem with `code`.
+pub fn invite_code_hash(account: &AccountId, code: &str) -> String {
+    near_primitives::hash::hash(format!("{}:{}", account, code).as_bytes())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub struct CurbClient {
+    rpc: JsonRpcClient,
+    contract_id: AccountId,
+    signer: Option<InMemorySigner>,
+    gas: Gas,
+    finality: Finality,
+}
+
+impl CurbClient {
+    /// Creates a read-only client for the contract deployed at `contract_id`.
+    pub fn new(rpc_url: &str, contract_id: AccountId) -> Self {
+        Self {
+            rpc: JsonRpcClient::connect(rpc_url),
+            contract_id,
+            signer: None,
+            gas: DEFAULT_GAS,
+            finality: Finality::Final,
+        }
+    }
+
+    /// Signs transactions with `signer`, which becomes the caller of every change method.
+    pub fn with_signer(mut self, signer: InMemorySigner) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    pub fn with_gas(mut self, gas: Gas) -> Self {
+        self.gas = gas;
+        self
+    }
+
+    /// Finality of the state view methods read, `Final` by default. `Finality::None` also sees
+    /// the effects of transactions that are not final yet, such as the client's own last call.
+    pub fn with_finality(mut self, finality: Finality) -> Self {
+        self.finality = finality;
+        self
+    }
+
+    pub fn contract_id(&self) -> &AccountId {
+        &self.contract_id
+    }
+
+    /// Account of the signer, if one is set.
+    pub fn account_id(&self) -> Option<AccountId> {
+        self.signer
+            .as_ref()
+            .map(|s| s.account_id.as_str().parse().unwrap())
+    }
+
+    pub async fn view<T: DeserializeOwned>(&self, method: &str, args: Value) -> Result<T> {
+        let response = self
+            .rpc
+            .call(methods::query::RpcQueryRequest {
+                block_reference: BlockReference::Finality(self.finality.clone()),
+                request: QueryRequest::CallFunction {
+                    account_id: self.near_contract_id()?,
+                    method_name: method.to_string(),
+                    args: args.to_string().into_bytes().into(),
+                },
+            })
+            .await
+            .map_err(|e| match e.handler_error() {
+                Some(e) => Error::from_failure(e.to_string()),
+                None => Error::Rpc(e.to_string()),
+            })?;
+        match response.kind {
+            QueryResponseKind::CallResult(result) => Ok(serde_json::from_slice(&result.result)?),
+            kind => Err(Error::UnexpectedResponse(format!("{:?}", kind))),
+        }
+    }
+
+    /// Calls a change method and decodes its return value, `()` for methods returning nothing.
+    pub async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        args: Value,
+        deposit: Balance,
+    ) -> Result<T> {
+        let action = Action::FunctionCall(FunctionCallAction {
+            method_name: method.to_string(),
+            args: args.to_string().into_bytes(),
+            gas: self.gas,
+            deposit,
+        });
+        let value = self.transact(vec![action]).await?;
+        if value.is_empty() {
+            Ok(serde_json::from_value(Value::Null)?)
+        } else {
+            Ok(serde_json::from_slice(&value)?)
+        }
+    }
+
+    async fn transact(&self, actions: Vec<Action>) -> Result<Vec<u8>> {
+        let signer = self.signer.as_ref().ok_or(Error::MissingSigner)?;
+        let (nonce, block_hash) = self.access_key_nonce(signer).await?;
+        let transaction = Transaction {
+            signer_id: signer.account_id.clone(),
+            public_key: signer.public_key.clone(),
+            nonce: nonce + 1,
+            receiver_id: self.near_contract_id()?,
+            block_hash,
+            actions,
+        };
+        let outcome = self
+            .rpc
+            .call(methods::broadcast_tx_commit::RpcBroadcastTxCommitRequest {
+                signed_transaction: transaction.sign(signer),
+            })
+            .await
+            .map_err(|e| match e.handler_error() {
+                Some(e) => Error::from_failure(e.to_string()),
+                None => Error::Rpc(e.to_string()),
+            })?;
+        match outcome.status {
+            FinalExecutionStatus::SuccessValue(value) => {
+                base64::decode(&value).map_err(|e| Error::UnexpectedResponse(e.to_string()))
+            }
+            FinalExecutionStatus::Failure(e) => Err(Error::from_failure(e.to_string())),
+            status => Err(Error::UnexpectedResponse(format!("{:?}", status))),
+        }
+    }
+
+    async fn access_key_nonce(&self, signer: &InMemorySigner) -> Result<(u64, CryptoHash)> {
+        let response = self
+            .rpc
+            .call(methods::query::RpcQueryRequest {
+                block_reference: BlockReference::latest(),
+                request: QueryRequest::ViewAccessKey {
+                    account_id: signer.account_id.clone(),
+                    public_key: signer.public_key.clone(),
+                },
+            })
+            .await
+            .map_err(|e| Error::Rpc(e.to_string()))?;
+        match response.kind {
+            QueryResponseKind::AccessKey(key) => Ok((key.nonce, response.block_hash)),
+            kind => Err(Error::UnexpectedResponse(format!("{:?}", kind))),
+        }
+    }
+
+    fn near_contract_id(&self) -> Result<near_primitives::types::AccountId> {
+        self.contract_id
+            .as_str()
+            .parse()
+            .map_err(|_| Error::AccountId(self.contract_id.to_string()))
+    }
+
+    /// Deploys `code` to the contract account and initializes it, the signer must be the
+    /// contract account itself.
+    pub async fn deploy(&self, code: Vec<u8>, name: String, owner: AccountId) -> Result<()> {
+        let init = Action::FunctionCall(FunctionCallAction {
+            method_name: "new".to_string(),
+            args: json!({ "name": name, "owner": owner })
+                .to_string()
+                .into_bytes(),
+            gas: self.gas,
+            deposit: 0,
+        });
+        self.transact(vec![
+            Action::DeployContract(DeployContractAction { code }),
+            init,
+        ])
+        .await?;
+        Ok(())
+    }
+
+    /// Joins the instance and returns its default channel, which new members join.
+    pub async fn join(&self) -> Result<Channel> {
+        self.call("join", json!({}), 0).await
+    }
+
+    pub async fn join_with_invite(&self, code: String) -> Result<Channel> {
+        self.call("join_with_invite", json!({ "code": code }), 0)
+            .await
+    }
+
+    pub async fn leave(&self, tombstone_messages: bool) -> Result<()> {
+        self.call(
+            "leave",
+            json!({ "tombstone_messages": tombstone_messages }),
+            0,
+        )
+        .await
+    }
+
+    pub async fn ping(&self) -> Result<()> {
+        self.call("ping", json!({}), 0).await
+    }
+
+    pub async fn set_status(
+        &self,
+        presence: Presence,
+        text: Option<String>,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        self.call(
+            "set_status",
+            json!({ "presence": presence, "text": text, "expires_at": expires_at }),
+            0,
+        )
+        .await
+    }
+
+    pub async fn get_rate_limit_budget(&self, account: AccountId) -> Result<RateLimitBudget> {
+        self.view("get_rate_limit_budget", json!({ "account": account }))
+            .await
+    }
+
+    /// Creates `group` with the caller as its first member and returns its normalized name.
+    pub async fn create_group(&self, group: Channel) -> Result<Channel> {
+        self.call("create_group", json!({ "group": group }), 0)
+            .await
+    }
+
+    pub async fn join_group(&self, group: Channel) -> Result<Channel> {
+        self.call("join_group", json!({ "group": group }), 0).await
+    }
+
+    pub async fn leave_group(&self, group: Channel) -> Result<Channel> {
+        self.call("leave_group", json!({ "group": group }), 0).await
+    }
+
+    pub async fn group_invite(&self, group: Channel, account: AccountId) -> Result<Channel> {
+        self.call(
+            "group_invite",
+            json!({ "group": group, "account": account }),
+            0,
+        )
+        .await
+    }
+
+    pub async fn kick(
+        &self,
+        group: Channel,
+        account: AccountId,
+        reason: Option<String>,
+    ) -> Result<Channel> {
+        self.call(
+            "kick",
+            json!({ "group": group, "account": account, "reason": reason }),
+            0,
+        )
+        .await
+    }
+
+    pub async fn mute(
+        &self,
+        group: Channel,
+        account: AccountId,
+        duration_ms: u64,
+        reason: Option<String>,
+    ) -> Result<Channel> {
+        self.call(
+            "mute",
+            json!({
+                "group": group,
+                "account": account,
+                "duration_ms": duration_ms,
+                "reason": reason,
+            }),
+            0,
+        )
+        .await
+    }
+
+    pub async fn unmute(
+        &self,
+        group: Channel,
+        account: AccountId,
+        reason: Option<String>,
+    ) -> Result<Channel> {
+        self.call(
+            "unmute",
+            json!({ "group": group, "account": account, "reason": reason }),
+            0,
+        )
+        .await
+    }
+
+    pub async fn ban(
+        &self,
+        group: Channel,
+        account: AccountId,
+        reason: Option<String>,
+    ) -> Result<Channel> {
+        self.call(
+            "ban",
+            json!({ "group": group, "account": account, "reason": reason }),
+            0,
+        )
+        .await
+    }
+
+    pub async fn unban(
+        &self,
+        group: Channel,
+        account: AccountId,
+        reason: Option<String>,
+    ) -> Result<Channel> {
+        self.call(
+            "unban",
+            json!({ "group": group, "account": account, "reason": reason }),
+            0,
+        )
+        .await
+    }
+
+    pub async fn ban_member(&self, account: AccountId, reason: Option<String>) -> Result<()> {
+        self.call(
+            "ban_member",
+            json!({ "account": account, "reason": reason }),
+            0,
+        )
+        .await
+    }
+
+    pub async fn unban_member(&self, account: AccountId, reason: Option<String>) -> Result<()> {
+        self.call(
+            "unban_member",
+            json!({ "account": account, "reason": reason }),
+            0,
+        )
+        .await
+    }
+
+    /// Sends a message to the DM with `account` or to `group`, returning the other account or the
+    /// channel as the contract does.
+    pub async fn send_message(
+        &self,
+        account: Option<AccountId>,
+        group: Option<Channel>,
+        message: String,
+        timestamp: u64,
+        parent_message: Option<MessageId>,
+        options: Option<MessageOptions>,
+    ) -> Result<Value> {
+        self.call(
+            "send_message",
+            json!({
+                "account": account,
+                "group": group,
+                "message": message,
+                "timestamp": timestamp,
+                "parent_message": parent_message,
+                "options": options,
+            }),
+            0,
+        )
+        .await
+    }
+
+    pub async fn read_message(
+        &self,
+        account: Option<AccountId>,
+        group: Option<Channel>,
+        message_id: MessageId,
+    ) -> Result<()> {
+        self.call(
+            "read_message",
+            json!({ "account": account, "group": group, "message_id": message_id }),
+            0,
+        )
+        .await
+    }
+
+    pub async fn toggle_reaction(&self, message_id: MessageId, reaction: String) -> Result<()> {
+        self.call(
+            "toggle_reaction",
+            json!({ "message_id": message_id, "reaction": reaction }),
+            0,
+        )
+        .await
+    }
+
+    pub async fn set_message_expiry(
+        &self,
+        account: Option<AccountId>,
+        group: Option<Channel>,
+        expiry_ms: Option<u64>,
+    ) -> Result<()> {
+        self.call(
+            "set_message_expiry",
+            json!({ "account": account, "group": group, "expiry_ms": expiry_ms }),
+            0,
+        )
+        .await
+    }
+
+    /// Removes expired messages of a chat or channel, returning how many were removed.
+    pub async fn remove_expired_messages(
+        &self,
+        accounts: Option<(AccountId, AccountId)>,
+        group: Option<Channel>,
+        limit: Option<u32>,
+    ) -> Result<u32> {
+        self.call(
+            "remove_expired_messages",
+            json!({ "accounts": accounts, "group": group, "limit": limit }),
+            0,
+        )
+        .await
+    }
+
+    pub async fn vote(&self, message_id: MessageId, options: Vec<u8>) -> Result<()> {
+        self.call(
+            "vote",
+            json!({ "message_id": message_id, "options": options }),
+            0,
+        )
+        .await
+    }
+
+    pub async fn get_poll(
+        &self,
+        message_id: MessageId,
+        viewer: Option<AccountId>,
+    ) -> Result<Option<PollResults>> {
+        self.view(
+            "get_poll",
+            json!({ "message_id": message_id, "viewer": viewer }),
+        )
+        .await
+    }
+
+    pub async fn unread_messages(&self, account: AccountId) -> Result<UnreadMessageInfo> {
+        self.view("unread_messages", json!({ "account": account }))
+            .await
+    }
+
+    pub async fn get_messages(
+        &self,
+        accounts: Option<(AccountId, AccountId)>,
+        group: Option<Channel>,
+        offset: Option<usize>,
+        length: Option<usize>,
+        viewer: Option<AccountId>,
+    ) -> Result<Vec<MessageWithReactionsAndThread>> {
+        self.view(
+            "get_messages",
+            json!({
+                "accounts": accounts,
+                "group": group,
+                "offset": offset,
+                "length": length,
+                "viewer": viewer,
+            }),
+        )
+        .await
+    }
+
+    /// Messages of `group` tagged `tag`, newest first, when the instance has `tagIndex` enabled.
+    /// `before` is the `next` cursor of the previous page.
+    pub async fn get_messages_by_tag(
+        &self,
+        group: Channel,
+        tag: String,
+        before: Option<u64>,
+        length: Option<usize>,
+        viewer: Option<AccountId>,
+    ) -> Result<TaggedMessages> {
+        self.view(
+            "get_messages_by_tag",
+            json!({
+                "group": group,
+                "tag": tag,
+                "before": before,
+                "length": length,
+                "viewer": viewer,
+            }),
+        )
+        .await
+    }
+
+    pub async fn get_messages_received_after(
+        &self,
+        group: Channel,
+        after: Option<(u64, MessageId)>,
+        length: Option<usize>,
+    ) -> Result<Vec<ReceivedMessage>> {
+        self.view(
+            "get_messages_received_after",
+            json!({ "group": group, "after": after, "length": length }),
+        )
+        .await
+    }
+
+    pub async fn get_reactors(
+        &self,
+        message_id: MessageId,
+        reaction: String,
+        offset: Option<usize>,
+        length: Option<usize>,
+    ) -> Result<Vec<AccountId>> {
+        self.view(
+            "get_reactors",
+            json!({
+                "message_id": message_id,
+                "reaction": reaction,
+                "offset": offset,
+                "length": length,
+            }),
+        )
+        .await
+    }
+
+    pub async fn get_members(&self, group: Option<Channel>) -> Result<Vec<UserInfo>> {
+        self.view("get_members", json!({ "group": group })).await
+    }
+
+    pub async fn get_groups(&self, account: Option<AccountId>) -> Result<Vec<Channel>> {
+        self.view("get_groups", json!({ "account": account })).await
+    }
+
+    pub async fn get_moderation_log(
+        &self,
+        group: Option<Channel>,
+        offset: Option<usize>,
+        length: Option<usize>,
+    ) -> Result<Vec<ModerationEntry>> {
+        self.view(
+            "get_moderation_log",
+            json!({ "group": group, "offset": offset, "length": length }),
+        )
+        .await
+    }
+
+    pub async fn get_banned(&self, group: Option<Channel>) -> Result<Vec<AccountId>> {
+        self.view("get_banned", json!({ "group": group })).await
+    }
+
+    pub async fn get_muted(&self, group: Channel) -> Result<HashMap<AccountId, u64>> {
+        self.view("get_muted", json!({ "group": group })).await
+    }
+
+    pub async fn get_keys(&self, account: AccountId) -> Result<Vec<PublicKey>> {
+        self.view("get_keys", json!({ "account": account })).await
+    }
+
+    pub async fn channel_info(&self, group: Channel) -> Result<Option<ChannelMetadata>> {
+        self.view("channel_info", json!({ "group": group })).await
+    }
+
+    pub async fn set_retention(&self, group: Channel, retention: RetentionPolicy) -> Result<()> {
+        self.call(
+            "set_retention",
+            json!({ "group": group, "retention": retention }),
+            0,
+        )
+        .await
+    }
+
+    /// Removes messages of `group` outside its retention policy, returning how many were removed.
+    pub async fn prune(&self, group: Channel, max_items: Option<u32>) -> Result<u32> {
+        self.call(
+            "prune",
+            json!({ "group": group, "max_items": max_items }),
+            0,
+        )
+        .await
+    }
+
+    pub async fn transfer_ownership(&self, new_owner: AccountId) -> Result<()> {
+        self.call("transfer_ownership", json!({ "new_owner": new_owner }), 0)
+            .await
+    }
+
+    pub async fn accept_ownership(&self) -> Result<()> {
+        self.call("accept_ownership", json!({}), 0).await
+    }
+
+    pub async fn set_name(&self, name: String) -> Result<()> {
+        self.call("set_name", json!({ "name": name }), 0).await
+    }
+
+    pub async fn set_config(&self, config: Config) -> Result<()> {
+        self.call("set_config", json!({ "config": config }), 0)
+            .await
+    }
+
+    pub async fn add_to_allowlist(&self, accounts: Vec<AccountId>) -> Result<()> {
+        self.call("add_to_allowlist", json!({ "accounts": accounts }), 0)
+            .await
+    }
+
+    pub async fn remove_from_allowlist(&self, accounts: Vec<AccountId>) -> Result<()> {
+        self.call("remove_from_allowlist", json!({ "accounts": accounts }), 0)
+            .await
+    }
+
+    pub async fn get_allowlist(&self) -> Result<Vec<AccountId>> {
+        self.view("get_allowlist", json!({})).await
+    }
+
+    /// Registers an invite by its [`invite_code_hash`].
+    pub async fn create_invite(&self, code_hash: String) -> Result<()> {
+        self.call("create_invite", json!({ "code_hash": code_hash }), 0)
+            .await
+    }
+
+    pub async fn revoke_invite(&self, code_hash: String) -> Result<()> {
+        self.call("revoke_invite", json!({ "code_hash": code_hash }), 0)
+            .await
+    }
+
+    pub async fn get_invites(&self, created_by: Option<AccountId>) -> Result<Vec<String>> {
+        self.view("get_invites", json!({ "created_by": created_by }))
+            .await
+    }
+
+    pub async fn remaining_invites(&self, account: AccountId) -> Result<u32> {
+        self.view("remaining_invites", json!({ "account": account }))
+            .await
+    }
+
+    pub async fn get_error_codes(&self) -> Result<Vec<ErrorInfo>> {
+        self.view("get_error_codes", json!({})).await
+    }
+
+    pub async fn get_config(&self) -> Result<Config> {
+        self.view("get_config", json!({})).await
+    }
+
+    pub async fn get_owner(&self) -> Result<AccountId> {
+        self.view("get_owner", json!({})).await
+    }
+
+    pub async fn get_pending_owner(&self) -> Result<Option<AccountId>> {
+        self.view("get_pending_owner", json!({})).await
+    }
+
+    pub async fn created_at(&self) -> Result<u64> {
+        self.view("created_at", json!({})).await
+    }
+
+    pub async fn get_name(&self) -> Result<String> {
+        self.view("get_name", json!({})).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invite_code_hash_binds_the_account() {
+        assert_eq!(
+            invite_code_hash(&"alice.near".parse().unwrap(), "welcome"),
+            "2103acbefefd106fd271106180ba9bf24f269f9da12d7e2f4b1e03f7554bf95d"
+        );
+    }
+}
@@ -0,0 +1,445 @@
+//! SQLite store of channel messages with an FTS5 index over their searchable text.
+
+use curb::{Message, MessageId, ReceivedMessage};
+use near_sdk::AccountId;
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Transaction};
+use serde::Serialize;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS messages (
+        id TEXT PRIMARY KEY,
+        channel TEXT NOT NULL,
+        parent TEXT,
+        sender TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        text TEXT NOT NULL,
+        json TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS messages_channel ON messages (channel, timestamp);
+    CREATE INDEX IF NOT EXISTS messages_sender ON messages (sender, timestamp);
+    CREATE INDEX IF NOT EXISTS messages_parent ON messages (parent);
+    CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts
+        USING fts5(text, content = 'messages', content_rowid = 'rowid');
+    CREATE TRIGGER IF NOT EXISTS messages_insert AFTER INSERT ON messages BEGIN
+        INSERT INTO messages_fts (rowid, text) VALUES (new.rowid, new.text);
+    END;
+    CREATE TRIGGER IF NOT EXISTS messages_delete AFTER DELETE ON messages BEGIN
+        INSERT INTO messages_fts (messages_fts, rowid, text) VALUES ('delete', old.rowid, old.text);
+    END;
+    CREATE TRIGGER IF NOT EXISTS messages_update AFTER UPDATE ON messages BEGIN
+        INSERT INTO messages_fts (messages_fts, rowid, text) VALUES ('delete', old.rowid, old.text);
+        INSERT INTO messages_fts (rowid, text) VALUES (new.rowid, new.text);
+    END;
+    CREATE TABLE IF NOT EXISTS channels (
+        name TEXT PRIMARY KEY,
+        created_at INTEGER NOT NULL,
+        received_at INTEGER,
+        last_id TEXT
+    );
+";
+
+/// Updates a message indexed before in place, so fetching it twice does not fail the sync. Unlike
+/// `INSERT OR REPLACE`, which deletes without firing triggers, this keeps the FTS index in step.
+const UPSERT: &str = "
+    INSERT INTO messages (id, channel, parent, sender, timestamp, text, json)
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+    ON CONFLICT (id) DO UPDATE SET
+        channel = excluded.channel,
+        parent = excluded.parent,
+        sender = excluded.sender,
+        timestamp = excluded.timestamp,
+        text = excluded.text,
+        json = excluded.json
+";
+
+/// Filters of a search, all optional and combined with AND.
+#[derive(Default)]
+pub struct Query {
+    /// Words that must all appear in the message text, poll or attachment filenames.
+    pub text: Option<String>,
+    pub sender: Option<AccountId>,
+    pub channel: Option<String>,
+    /// Inclusive range of message timestamps in milliseconds.
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    /// Only replies in the thread of this message.
+    pub thread: Option<MessageId>,
+    pub limit: usize,
+}
+
+#[derive(Serialize)]
+pub struct Hit {
+    pub channel: String,
+    /// Thread the message is a reply in.
+    pub parent: Option<MessageId>,
+    pub message: Message,
+}
+
+/// How far a channel has been indexed.
+pub struct ChannelState {
+    /// Creation time of the channel the messages were read from, which changes when the channel
+    /// is deleted and created again under the same name.
+    pub created_at: u64,
+    /// `(receivedAt, id)` of the last message indexed, the `after` cursor of the next sync.
+    pub cursor: Option<(u64, MessageId)>,
+}
+
+pub struct Index {
+    conn: Connection,
+}
+
+impl Index {
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    #[cfg(test)]
+    pub fn in_memory() -> rusqlite::Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(conn: Connection) -> rusqlite::Result<Self> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn })
+    }
+
+    pub fn channel_state(&self, channel: &str) -> rusqlite::Result<Option<ChannelState>> {
+        self.conn
+            .query_row(
+                "SELECT created_at, received_at, last_id FROM channels WHERE name = ?1",
+                params![channel],
+                |row| {
+                    let received_at: Option<i64> = row.get(1)?;
+                    let last_id: Option<MessageId> = row.get(2)?;
+                    Ok(ChannelState {
+                        created_at: row.get::<_, i64>(0)? as u64,
+                        cursor: received_at.zip(last_id).map(|(at, id)| (at as u64, id)),
+                    })
+                },
+            )
+            .optional()
+    }
+
+    /// Replaces the indexed messages of `channel`, created at `created_at`, with its whole
+    /// history, so messages removed from the contract disappear from the index too.
+    pub fn replace_channel(
+        &mut self,
+        channel: &str,
+        created_at: u64,
+        messages: &[ReceivedMessage],
+    ) -> rusqlite::Result<()> {
+        let tx = self.conn.transaction()?;
+        tx.execute("DELETE FROM messages WHERE channel = ?1", params![channel])?;
+        tx.execute(
+            "INSERT OR REPLACE INTO channels (name, created_at) VALUES (?1, ?2)",
+            params![channel, created_at as i64],
+        )?;
+        Self::insert(&tx, channel, messages)?;
+        tx.commit()
+    }
+
+    /// Indexes `messages` received in `channel` since its last sync, in the order they were
+    /// received, and moves its cursor past them.
+    pub fn add_messages(
+        &mut self,
+        channel: &str,
+        messages: &[ReceivedMessage],
+    ) -> rusqlite::Result<()> {
+        let tx = self.conn.transaction()?;
+        Self::insert(&tx, channel, messages)?;
+        tx.commit()
+    }
+
+    fn insert(
+        tx: &Transaction,
+        channel: &str,
+        messages: &[ReceivedMessage],
+    ) -> rusqlite::Result<()> {
+        let mut upsert = tx.prepare(UPSERT)?;
+        for ReceivedMessage { parent, message } in messages {
+            upsert.execute(params![
+                message.id,
+                channel,
+                parent,
+                message.sender.as_str(),
+                message.timestamp as i64,
+                searchable_text(message),
+                serde_json::to_string(message).unwrap(),
+            ])?;
+        }
+        if let Some(last) = messages.last().map(|m| &m.message) {
+            tx.execute(
+                "UPDATE channels SET received_at = ?2, last_id = ?3 WHERE name = ?1",
+                params![channel, last.received_at as i64, last.id],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Drops the messages of channels not in `channels`, e.g. deleted ones.
+    pub fn retain_channels(&mut self, channels: &[String]) -> rusqlite::Result<()> {
+        let indexed: Vec<String> = self
+            .conn
+            .prepare("SELECT name FROM channels UNION SELECT DISTINCT channel FROM messages")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        for channel in indexed.iter().filter(|c| !channels.contains(c)) {
+            self.conn
+                .execute("DELETE FROM messages WHERE channel = ?1", params![channel])?;
+            self.conn
+                .execute("DELETE FROM channels WHERE name = ?1", params![channel])?;
+        }
+        Ok(())
+    }
+
+    /// Drops messages that expired by the block time `now`, as the contract hides them without
+    /// a change a sync would see.
+    pub fn remove_expired(&mut self, now: u64) -> rusqlite::Result<usize> {
+        self.conn.execute(
+            "DELETE FROM messages WHERE json_extract(json, '$.expiresAt') <= ?1",
+            params![now as i64],
+        )
+    }
+
+    /// Messages matching `query`, best text matches first when searching text and most recent
+    /// first otherwise.
+    pub fn search(&self, query: &Query) -> rusqlite::Result<Vec<Hit>> {
+        let mut sql = "SELECT m.channel, m.parent, m.json FROM messages m".to_string();
+        let mut conditions = vec![];
+        let mut args: Vec<Value> = vec![];
+        if let Some(text) = &query.text {
+            sql.push_str(" JOIN messages_fts f ON f.rowid = m.rowid");
+            conditions.push("messages_fts MATCH ?");
+            args.push(Value::Text(fts_query(text)));
+        }
+        if let Some(sender) = &query.sender {
+            conditions.push("m.sender = ?");
+            args.push(Value::Text(sender.to_string()));
+        }
+        if let Some(channel) = &query.channel {
+            conditions.push("m.channel = ?");
+            args.push(Value::Text(channel.clone()));
+        }
+        if let Some(from) = query.from {
+            conditions.push("m.timestamp >= ?");
+            args.push(Value::Integer(from as i64));
+        }
+        if let Some(to) = query.to {
+            conditions.push("m.timestamp <= ?");
+            args.push(Value::Integer(to as i64));
+        }
+        if let Some(thread) = &query.thread {
+            conditions.push("m.parent = ?");
+            args.push(Value::Text(thread.clone()));
+        }
+        if !conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&conditions.join(" AND "));
+        }
+        sql.push_str(match query.text {
+            Some(_) => " ORDER BY f.rank, m.timestamp DESC",
+            None => " ORDER BY m.timestamp DESC",
+        });
+        sql.push_str(" LIMIT ?");
+        args.push(Value::Integer(query.limit as i64));
+
+        let mut statement = self.conn.prepare(&sql)?;
+        let hits = statement.query_map(params_from_iter(args), |row| {
+            let json: String = row.get(2)?;
+            Ok(Hit {
+                channel: row.get(0)?,
+                parent: row.get(1)?,
+                message: serde_json::from_str(&json).unwrap(),
+            })
+        })?;
+        hits.collect()
+    }
+}
+
+/// Text a message is found by: its text, its poll and the names of its attachments.
+fn searchable_text(message: &Message) -> String {
+    let mut parts = vec![message.text.as_str()];
+    if let Some(poll) = &message.poll {
+        parts.push(&poll.question);
+        parts.extend(poll.options.iter().map(String::as_str));
+    }
+    parts.extend(message.attachments.iter().map(|a| a.filename.as_str()));
+    parts.join("\n")
+}
+
+/// Quotes every word of `text` so FTS5 query syntax in it is matched literally.
+fn fts_query(text: &str) -> String {
+    text.split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: &str, sender: &str, timestamp: u64, text: &str) -> Message {
+        Message {
+            timestamp,
+            sender: sender.parse().unwrap(),
+            id: id.to_string(),
+            text: text.to_string(),
+            deleted: false,
+            reply_to: None,
+            forwarded_from: None,
+            attachments: vec![],
+            format: Default::default(),
+            link_previews: vec![],
+            poll: None,
+            expires_at: None,
+            received_at: timestamp,
+        }
+    }
+
+    fn received(parent: Option<&str>, message: Message) -> ReceivedMessage {
+        ReceivedMessage {
+            parent: parent.map(str::to_string),
+            message,
+        }
+    }
+
+    fn ids(hits: Vec<Hit>) -> Vec<String> {
+        hits.into_iter().map(|h| h.message.id).collect()
+    }
+
+    fn index() -> Index {
+        let mut index = Index::in_memory().unwrap();
+        index
+            .replace_channel(
+                "general",
+                1,
+                &[
+                    received(
+                        None,
+                        message("a", "alice.near", 1, "Deploying the contract today"),
+                    ),
+                    received(
+                        Some("a"),
+                        message("b", "bob.near", 2, "contract looks good"),
+                    ),
+                    received(None, message("c", "bob.near", 3, "lunch?")),
+                ],
+            )
+            .unwrap();
+        index
+            .replace_channel(
+                "dev",
+                1,
+                &[received(
+                    None,
+                    message("d", "alice.near", 4, "contract \"tests\" fail"),
+                )],
+            )
+            .unwrap();
+        index
+    }
+
+    fn query() -> Query {
+        Query {
+            limit: 10,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn searches_text_and_filters() {
+        let index = index();
+        let text = |text: &str| Query {
+            text: Some(text.to_string()),
+            ..query()
+        };
+        assert_eq!(ids(index.search(&text("contract")).unwrap()).len(), 3);
+        assert_eq!(ids(index.search(&text("contract today")).unwrap()), ["a"]);
+        // Query syntax is matched literally.
+        assert_eq!(ids(index.search(&text("\"tests\"")).unwrap()), ["d"]);
+        assert!(index.search(&text("fail OR lunch")).unwrap().is_empty());
+        assert_eq!(
+            ids(index
+                .search(&Query {
+                    sender: Some("alice.near".parse().unwrap()),
+                    ..text("contract")
+                })
+                .unwrap()),
+            ["d", "a"]
+        );
+        assert_eq!(
+            ids(index
+                .search(&Query {
+                    channel: Some("general".to_string()),
+                    from: Some(2),
+                    to: Some(3),
+                    ..query()
+                })
+                .unwrap()),
+            ["c", "b"]
+        );
+        assert_eq!(
+            ids(index
+                .search(&Query {
+                    thread: Some("a".to_string()),
+                    ..query()
+                })
+                .unwrap()),
+            ["b"]
+        );
+    }
+
+    #[test]
+    fn replacing_a_channel_drops_removed_messages() {
+        let mut index = index();
+        index
+            .replace_channel(
+                "general",
+                1,
+                &[received(None, message("c", "bob.near", 3, "lunch?"))],
+            )
+            .unwrap();
+        index.retain_channels(&["general".to_string()]).unwrap();
+        assert_eq!(ids(index.search(&query()).unwrap()), ["c"]);
+        let contract = Query {
+            text: Some("contract".to_string()),
+            ..query()
+        };
+        assert!(index.search(&contract).unwrap().is_empty());
+    }
+
+    #[test]
+    fn added_messages_advance_the_cursor_and_update_in_place() {
+        let mut index = index();
+        assert_eq!(
+            index.channel_state("general").unwrap().unwrap().cursor,
+            Some((3, "c".to_string()))
+        );
+        assert!(index.channel_state("random").unwrap().is_none());
+
+        // A message fetched again replaces its indexed text instead of failing.
+        let mut edited = message("c", "bob.near", 3, "dinner?");
+        edited.expires_at = Some(10);
+        let mut late = message("e", "carol.near", 0, "late lunch");
+        late.received_at = 4;
+        index
+            .add_messages("general", &[received(None, edited), received(None, late)])
+            .unwrap();
+        let text = |text: &str| Query {
+            text: Some(text.to_string()),
+            ..query()
+        };
+        assert_eq!(ids(index.search(&text("lunch")).unwrap()), ["e"]);
+        assert_eq!(ids(index.search(&text("dinner")).unwrap()), ["c"]);
+        assert_eq!(
+            index.channel_state("general").unwrap().unwrap().cursor,
+            Some((4, "e".to_string()))
+        );
+
+        assert_eq!(index.remove_expired(9).unwrap(), 0);
+        assert_eq!(index.remove_expired(10).unwrap(), 1);
+        assert!(index.search(&text("dinner")).unwrap().is_empty());
+    }
+}
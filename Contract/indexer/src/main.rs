@@ -0,0 +1,144 @@
+//! `curb-indexer` keeps a local SQLite full-text index of a Curb instance's channels, replayed
+//! through the contract's view methods, and searches it.
+
+use std::error::Error;
+use std::time::Duration;
+
+use clap::{Parser, Subcommand};
+use near_sdk::AccountId;
+
+use curb_client::CurbClient;
+
+/// Sandbox environment of the contract's own tests.
+#[cfg(test)]
+#[path = "../../tests/common/mod.rs"]
+mod common;
+mod index;
+mod sync;
+
+use index::{Index, Query};
+
+#[derive(Parser)]
+#[command(
+    name = "curb-indexer",
+    about = "Index and search Curb channel messages"
+)]
+struct Cli {
+    /// JSON-RPC endpoint of the network the contract is deployed on.
+    #[arg(long, env = "CURB_RPC_URL", default_value = "http://localhost:3030")]
+    rpc_url: String,
+    /// Account the Curb contract is deployed to.
+    #[arg(long, env = "CURB_CONTRACT")]
+    contract: AccountId,
+    /// SQLite database the index is kept in.
+    #[arg(long, env = "CURB_INDEX", default_value = "curb-index.sqlite")]
+    db: String,
+    /// Print results as JSON.
+    #[arg(long)]
+    json: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Index the messages channels received since the last sync.
+    Sync {
+        /// Read every channel from the start, dropping messages pruned or tombstoned since they
+        /// were indexed.
+        #[arg(long)]
+        full: bool,
+    },
+    /// Sync repeatedly.
+    Follow {
+        /// Polling interval in seconds.
+        #[arg(long, default_value_t = 10)]
+        interval: u64,
+        /// Every how many syncs to read every channel from the start, 0 for never.
+        #[arg(long, default_value_t = 60)]
+        full_every: u64,
+    },
+    /// Search indexed messages, most relevant first when searching text.
+    Search {
+        /// Words that must all appear in the message.
+        text: Option<String>,
+        #[arg(long)]
+        sender: Option<AccountId>,
+        #[arg(long)]
+        channel: Option<String>,
+        /// Earliest message timestamp, in milliseconds.
+        #[arg(long)]
+        from: Option<u64>,
+        /// Latest message timestamp, in milliseconds.
+        #[arg(long)]
+        to: Option<u64>,
+        /// Only replies in the thread of this message.
+        #[arg(long)]
+        thread: Option<String>,
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+}
+
+async fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+    let client = CurbClient::new(&cli.rpc_url, cli.contract);
+    let mut index = Index::open(&cli.db)?;
+    match cli.command {
+        Command::Sync { full } => {
+            let indexed = sync::sync(&client, &mut index, full).await?;
+            println!("indexed {} messages", indexed);
+        }
+        Command::Follow {
+            interval,
+            full_every,
+        } => {
+            for pass in 1.. {
+                let full = full_every != 0 && pass % full_every == 0;
+                match sync::sync(&client, &mut index, full).await {
+                    Ok(indexed) => println!("indexed {} messages", indexed),
+                    Err(e) => eprintln!("sync failed: {}", e),
+                }
+                tokio::time::sleep(Duration::from_secs(interval)).await;
+            }
+        }
+        Command::Search {
+            text,
+            sender,
+            channel,
+            from,
+            to,
+            thread,
+            limit,
+        } => {
+            let hits = index.search(&Query {
+                text,
+                sender,
+                channel,
+                from,
+                to,
+                thread,
+                limit,
+            })?;
+            for hit in hits {
+                if cli.json {
+                    println!("{}", serde_json::to_string(&hit)?);
+                } else {
+                    let message = &hit.message;
+                    println!(
+                        "[{}] #{} {} {}: {}",
+                        message.timestamp, hit.channel, message.id, message.sender, message.text
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run(Cli::parse()).await {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
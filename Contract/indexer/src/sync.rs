@@ -0,0 +1,154 @@
+//! Replays channel history from the contract's view methods into the index.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use curb::{Channel, MessageId, ReceivedMessage};
+use curb_client::CurbClient;
+
+use crate::index::Index;
+
+/// Messages fetched per `get_messages_received_after` call.
+const PAGE: usize = 100;
+
+/// Indexes the messages every channel received since the last sync, returning how many were
+/// fetched. A channel seen for the first time or created again, or every channel when `full` is
+/// set, is read from the start and replaces what was indexed, which also drops messages pruned
+/// or tombstoned since. Direct messages are private to their parties and are not indexed.
+pub async fn sync(
+    client: &CurbClient,
+    index: &mut Index,
+    full: bool,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let channels = client.get_groups(None).await?;
+    let mut total = 0;
+    for channel in channels.iter() {
+        let created_at = match client.channel_info(channel.clone()).await? {
+            Some(info) => info.created_at,
+            // Deleted since it was listed.
+            None => continue,
+        };
+        let state = index
+            .channel_state(&channel.name)?
+            .filter(|state| !full && state.created_at == created_at);
+        match state {
+            Some(state) => {
+                let messages = received_after(client, channel, state.cursor).await?;
+                total += messages.len();
+                index.add_messages(&channel.name, &messages)?;
+            }
+            None => {
+                let messages = received_after(client, channel, None).await?;
+                total += messages.len();
+                index.replace_channel(&channel.name, created_at, &messages)?;
+            }
+        }
+    }
+    let names: Vec<String> = channels.into_iter().map(|c| c.name).collect();
+    index.retain_channels(&names)?;
+    index.remove_expired(now_ms())?;
+    Ok(total)
+}
+
+/// Messages of `channel` received after `cursor`, paged by receive order so messages sent with an
+/// earlier client timestamp or removed in between neither shift nor repeat a page.
+async fn received_after(
+    client: &CurbClient,
+    channel: &Channel,
+    mut cursor: Option<(u64, MessageId)>,
+) -> Result<Vec<ReceivedMessage>, Box<dyn std::error::Error>> {
+    let mut messages = vec![];
+    loop {
+        let page = client
+            .get_messages_received_after(channel.clone(), cursor, Some(PAGE))
+            .await?;
+        let fetched = page.len();
+        cursor = page
+            .last()
+            .map(|m| (m.message.received_at, m.message.id.clone()));
+        messages.extend(page);
+        if fetched < PAGE {
+            return Ok(messages);
+        }
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Env;
+    use crate::index::Query;
+    use curb::RetentionPolicy;
+
+    #[tokio::test]
+    #[ignore = "requires near-sandbox, run with `cargo test -- --ignored`"]
+    async fn follows_a_sandbox_instance() -> Result<(), Box<dyn std::error::Error>> {
+        let env = Env::new().await?;
+        let alice = env.client(&env.alice)?;
+        alice.join().await?;
+        let general = Channel {
+            name: "general".to_string(),
+        };
+        let send = |text: &str, timestamp, parent| {
+            alice.send_message(
+                None,
+                Some(general.clone()),
+                text.to_string(),
+                timestamp,
+                parent,
+                None,
+            )
+        };
+        let search = |index: &Index, text: &str| -> rusqlite::Result<Vec<String>> {
+            let hits = index.search(&Query {
+                text: Some(text.to_string()),
+                limit: 10,
+                ..Default::default()
+            })?;
+            Ok(hits.into_iter().map(|hit| hit.message.text).collect())
+        };
+        send("deploying the contract", 10, None).await?;
+        send("lunch?", 5, None).await?;
+        let parent = alice
+            .get_messages(None, Some(general.clone()), None, None, None)
+            .await?
+            .pop()
+            .unwrap()
+            .id;
+        send("contract looks good", 11, Some(parent)).await?;
+
+        let mut index = Index::in_memory()?;
+        assert_eq!(sync(&alice, &mut index, false).await?, 3);
+        assert_eq!(search(&index, "contract")?.len(), 2);
+
+        // Only new messages are fetched, including one sent with an earlier client timestamp.
+        send("contract tests pass", 1, None).await?;
+        assert_eq!(sync(&alice, &mut index, false).await?, 1);
+        assert_eq!(sync(&alice, &mut index, false).await?, 0);
+        assert_eq!(search(&index, "contract")?.len(), 3);
+
+        // Pruned messages are dropped by the next full sync.
+        alice
+            .set_retention(
+                general.clone(),
+                RetentionPolicy {
+                    max_messages: Some(1),
+                    max_age_ms: None,
+                },
+            )
+            .await?;
+        assert_eq!(alice.prune(general, None).await?, 2);
+        assert_eq!(sync(&alice, &mut index, false).await?, 0);
+        assert_eq!(search(&index, "lunch")?, ["lunch?"]);
+        assert_eq!(sync(&alice, &mut index, true).await?, 2);
+        assert!(search(&index, "lunch")?.is_empty());
+        assert_eq!(search(&index, "contract")?.len(), 2);
+        Ok(())
+    }
+}
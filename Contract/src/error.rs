@@ -0,0 +1,184 @@
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json;
+use schemars::JsonSchema;
+
+use std::fmt;
+
+/// Declares `CurbError` together with `CurbError::ALL` and `CurbError::message`, so a new variant
+/// cannot be left out of either.
+macro_rules! errors {
+    ($(#[$meta:meta])* pub enum $name:ident { $($variant:ident => $message:literal,)* }) => {
+        $(#[$meta])*
+        pub enum $name {
+            $($variant,)*
+        }
+
+        impl $name {
+            pub const ALL: &'static [$name] = &[$($name::$variant,)*];
+
+            pub fn message(&self) -> &'static str {
+                match self {
+                    $($name::$variant => $message,)*
+                }
+            }
+        }
+    };
+}
+
+errors! {
+    /// Every failure of a `Curb` method panics with one of these errors, serialized as
+    /// `{"code":"NOT_A_MEMBER","message":"Not a member"}` so clients can branch on `code`.
+    ///
+    /// Codes are stable: variants may be added but existing codes are never renamed or reused.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+    #[serde(crate = "near_sdk::serde", rename_all = "SCREAMING_SNAKE_CASE")]
+    pub enum CurbError {
+        NotAMember => "Not a member",
+        AlreadyAMember => "Already a member",
+        OtherAccountNotAMember => "Other account is not a member",
+        AccountBanned => "Account is banned",
+        RegistrationClosed => "Registration is closed",
+        NotOnAllowlist => "Not on the allowlist",
+        InviteCodeRequired => "Invite code required",
+        InvalidInviteCode => "Invalid invite code",
+        InvalidInviteCodeHash => "Invalid invite code hash",
+        InviteAlreadyExists => "Invite already exists",
+        InviteQuotaExceeded => "Invite quota exceeded",
+        NotInviteCreator => "Not the invite creator",
+        NotTheOwner => "Not the owner",
+        NotThePendingOwner => "Not the pending owner",
+        NotAModerator => "Not a moderator",
+        InvalidName => "Name is empty",
+        InvalidConfig => "Invalid configuration",
+        RateLimitExceeded => "Rate limit exceeded",
+        MissingTarget => "Either account or group need to be provided",
+        GroupAlreadyExists => "Group already exists",
+        GroupDoesNotExist => "Group does not exist",
+        NotAGroupMember => "Not a group member",
+        BannedFromGroup => "Banned from group",
+        MutedInGroup => "Muted in group",
+        AlreadyBanned => "Already banned",
+        NotBanned => "Not banned",
+        NotMuted => "Not muted",
+        ChatDoesNotExist => "Chat does not exist",
+        MessageDoesNotExist => "Message does not exist",
+        GroupNameEmpty => "Group name is empty",
+        GroupNameTooLong => "Group name is too long",
+        GroupNameInvalidCharacters => "Group name contains invalid characters",
+        MessageTooLong => "Message is too long",
+        ReactionEmpty => "Reaction is empty",
+        ReactionTooLong => "Reaction is too long",
+        ReactionNotAllowed => "Reaction is not allowed",
+        TooManyReactions => "Message has too many different reactions",
+        InvalidReplyTarget => "Replied message is not in this conversation",
+        InvalidParentMessage => "Thread parent is not a top-level message of this conversation",
+        TooManyAttachments => "Message has too many attachments",
+        AttachmentTooLarge => "Attachment is too large",
+        InvalidAttachment => "Attachment metadata is invalid",
+        InvalidCodeLanguage => "Code language is invalid",
+        TooManyLinkPreviews => "Message has too many link previews",
+        InvalidLinkPreview => "Link preview is invalid",
+        InvalidPoll => "Poll is invalid",
+        NotAPoll => "Message is not a poll",
+        PollClosed => "Poll is closed",
+        InvalidPollOption => "Poll option is invalid",
+        InvalidExpiry => "Expiry must be in the future",
+        InvalidRetentionPolicy => "Retention limits must be positive",
+        InvalidStatus => "Status text is invalid",
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ErrorInfo {
+    pub code: CurbError,
+    pub message: String,
+}
+
+impl CurbError {
+    pub fn info(&self) -> ErrorInfo {
+        ErrorInfo {
+            code: *self,
+            message: self.message().to_string(),
+        }
+    }
+
+    #[cfg(not(test))]
+    pub fn panic(self) -> ! {
+        near_sdk::env::panic_str(&self.to_string())
+    }
+
+    /// The mocked `panic_str` aborts the test binary, so unit tests unwind with the error itself
+    /// and catch it with [`catch`].
+    #[cfg(test)]
+    pub fn panic(self) -> ! {
+        std::panic::panic_any(self)
+    }
+}
+
+impl fmt::Display for CurbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&serde_json::to_string(&self.info()).unwrap())
+    }
+}
+
+/// Panics with `error` unless `condition` holds, the `CurbError` counterpart of `require!`.
+pub fn ensure(condition: bool, error: CurbError) {
+    if !condition {
+        error.panic()
+    }
+}
+
+/// Runs `f`, returning the `CurbError` it panicked with. Storage written before the panic is not
+/// rolled back.
+#[cfg(test)]
+pub(crate) fn catch<R>(f: impl FnOnce() -> R) -> Result<R, CurbError> {
+    static QUIET: std::sync::Once = std::sync::Once::new();
+    QUIET.call_once(|| {
+        let default = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            if !info.payload().is::<CurbError>() {
+                default(info)
+            }
+        }));
+    });
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).map_err(|payload| {
+        match payload.downcast::<CurbError>() {
+            Ok(error) => *error,
+            Err(payload) => std::panic::resume_unwind(payload),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_serializes_code_and_message() {
+        assert_eq!(
+            CurbError::NotAMember.to_string(),
+            r#"{"code":"NOT_A_MEMBER","message":"Not a member"}"#
+        );
+    }
+
+    #[test]
+    fn error_codes_are_unique() {
+        let mut codes: Vec<String> = CurbError::ALL
+            .iter()
+            .map(|e| serde_json::to_string(e).unwrap())
+            .collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), CurbError::ALL.len());
+    }
+
+    #[test]
+    fn catch_returns_the_error() {
+        assert_eq!(
+            catch(|| CurbError::NotAMember.panic()),
+            Err(CurbError::NotAMember)
+        );
+        assert_eq!(catch(|| ensure(true, CurbError::NotAMember)), Ok(()));
+    }
+}
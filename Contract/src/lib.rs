@@ -1,21 +1,54 @@
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::serde_json;
-use near_sdk::store::{UnorderedMap, UnorderedSet};
-use near_sdk::{env, near_bindgen, require, AccountId, PanicOnDefault, PublicKey};
+use near_sdk::store::{LookupMap, UnorderedMap, UnorderedSet, Vector};
+use near_sdk::{env, near_bindgen, AccountId, Balance, PanicOnDefault, Promise, PublicKey};
+use schemars::JsonSchema;
 
 use std::collections::HashMap;
 use std::fmt::Write;
 
-type MessageId = String;
+mod error;
+#[cfg(test)]
+mod model;
+mod validation;
+
+use error::ensure;
+pub use error::{CurbError, ErrorInfo};
+
+pub type MessageId = String;
 
 const ACTIVE_MS_THRESHOLD: u64 = 30 * 1000;
+const AWAY_MS_THRESHOLD: u64 = 10 * 60 * 1000;
+const MAX_MESSAGE_LENGTH: u32 = 4096;
+const MAX_CHANNEL_NAME_LENGTH: u32 = 64;
+const MAX_REACTION_GRAPHEMES: u32 = 1;
+const MAX_REACTIONS_PER_MESSAGE: u32 = 20;
+const MAX_ATTACHMENTS_PER_MESSAGE: u32 = 10;
+const MAX_ATTACHMENT_SIZE: u64 = 100 * 1024 * 1024;
+const MAX_LINK_PREVIEWS_PER_MESSAGE: u32 = 4;
+const MAX_POLL_OPTIONS: u32 = 10;
+const MAX_TAGS_PER_MESSAGE: usize = 5;
+const MAX_MESSAGES_PER_TAG: u32 = 100;
+const MAX_TAGS_PER_CHANNEL: u32 = 1000;
+/// Messages `remove_expired_messages` and `prune` remove per call unless given a limit.
+const MESSAGES_REMOVED_PER_CALL: u32 = 50;
+/// Characters of a replied or forwarded message quoted in `get_messages` output.
+const PREVIEW_LENGTH: usize = 100;
+const INVITE_QUOTA: u32 = 5;
+const MESSAGES_PER_MINUTE: u32 = 60;
+const REACTIONS_PER_MINUTE: u32 = 120;
+const CHANNELS_PER_DAY: u32 = 10;
+
+const MINUTE_MS: u64 = 60 * 1000;
+const DAY_MS: u64 = 24 * 60 * MINUTE_MS;
 
 #[derive(
     BorshDeserialize,
     BorshSerialize,
     Serialize,
     Deserialize,
+    JsonSchema,
     PartialEq,
     Eq,
     PartialOrd,
@@ -28,8 +61,27 @@ pub struct Channel {
     pub name: String,
 }
 
+impl Channel {
+    /// The NFC form `create_group` stores names in, so lookups match however a client encoded
+    /// the name.
+    fn normalized(self) -> Channel {
+        Channel {
+            name: validation::normalize(&self.name),
+        }
+    }
+}
+
 #[derive(
-    BorshDeserialize, BorshSerialize, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone,
+    BorshDeserialize,
+    BorshSerialize,
+    Serialize,
+    Deserialize,
+    JsonSchema,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Clone,
 )]
 #[serde(crate = "near_sdk::serde")]
 pub struct ChannelWithId {
@@ -38,16 +90,76 @@ pub struct ChannelWithId {
 }
 
 #[derive(
-    BorshDeserialize, BorshSerialize, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone,
+    BorshDeserialize,
+    BorshSerialize,
+    Serialize,
+    Deserialize,
+    JsonSchema,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Clone,
 )]
 #[serde(crate = "near_sdk::serde")]
 pub struct UserInfo {
     pub id: AccountId,
+    /// Whether the member was seen within `activeMsThreshold` and does not appear offline.
     pub active: bool,
+    pub presence: Presence,
+    /// Custom status text until the status expires.
+    #[serde(rename = "statusText")]
+    pub status_text: Option<String>,
+    /// Timestamp of the member's last call in milliseconds.
+    #[serde(rename = "lastSeen")]
+    pub last_seen: u64,
+}
+
+/// Presence of a member as shown to others.
+#[derive(
+    BorshDeserialize,
+    BorshSerialize,
+    Serialize,
+    Deserialize,
+    JsonSchema,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Clone,
+    Copy,
+    Debug,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum Presence {
+    Online,
+    Away,
+    DoNotDisturb,
+    Offline,
+}
+
+/// Presence and custom status text a member chose with `set_status`.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Status {
+    pub presence: Presence,
+    pub text: Option<String>,
+    /// When presence follows activity again and the text is cleared, in milliseconds.
+    #[serde(rename = "expiresAt")]
+    pub expires_at: Option<u64>,
 }
 
 #[derive(
-    PartialEq, Eq, PartialOrd, Ord, BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    BorshDeserialize,
+    BorshSerialize,
+    Serialize,
+    Deserialize,
+    JsonSchema,
+    Clone,
 )]
 #[serde(crate = "near_sdk::serde")]
 pub struct Message {
@@ -55,30 +167,286 @@ pub struct Message {
     pub sender: AccountId,
     pub id: MessageId,
     pub text: String,
+    /// Set when the sender left the instance and asked for their messages to be removed.
+    #[serde(default)]
+    pub deleted: bool,
+    /// Earlier top-level message of the same conversation this message replies to.
+    #[serde(rename = "replyTo", default)]
+    pub reply_to: Option<MessageId>,
+    /// Top-level message of any conversation visible to the sender this message forwards.
+    #[serde(rename = "forwardedFrom", default)]
+    pub forwarded_from: Option<MessageId>,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    /// How clients should render `text`.
+    #[serde(default)]
+    pub format: MessageFormat,
+    #[serde(rename = "linkPreviews", default)]
+    pub link_previews: Vec<LinkPreview>,
+    #[serde(default)]
+    pub poll: Option<Poll>,
+    /// Block time in milliseconds after which the message is hidden and can be removed.
+    #[serde(rename = "expiresAt", default)]
+    pub expires_at: Option<u64>,
+    /// Block time in milliseconds the message was sent at, which retention ages count from as
+    /// `timestamp` is set by the sending client.
+    #[serde(rename = "receivedAt", default)]
+    pub received_at: u64,
+}
+
+impl Message {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Optional parts of a message sent with `send_message`, see `Message` for their meaning.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Default)]
+#[serde(crate = "near_sdk::serde", default)]
+pub struct MessageOptions {
+    #[serde(rename = "replyTo")]
+    pub reply_to: Option<MessageId>,
+    #[serde(rename = "forwardedFrom")]
+    pub forwarded_from: Option<MessageId>,
+    pub attachments: Vec<Attachment>,
+    pub format: MessageFormat,
+    #[serde(rename = "linkPreviews")]
+    pub link_previews: Vec<LinkPreview>,
+    pub poll: Option<Poll>,
+    /// Block time in milliseconds after which the message expires, the conversation's expiry
+    /// applies when unset.
+    #[serde(rename = "expiresAt")]
+    pub expires_at: Option<u64>,
+}
+
+/// A question members of a channel vote on, with its votes kept apart from the message.
+#[derive(
+    BorshDeserialize,
+    BorshSerialize,
+    Serialize,
+    Deserialize,
+    JsonSchema,
+    Clone,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Debug,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Poll {
+    pub question: String,
+    pub options: Vec<String>,
+    /// Whether members may vote for more than one option.
+    #[serde(rename = "multipleChoice", default)]
+    pub multiple_choice: bool,
+    /// Block time in milliseconds after which votes are no longer accepted.
+    #[serde(default)]
+    pub deadline: Option<u64>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PollResults {
+    pub question: String,
+    pub options: Vec<String>,
+    #[serde(rename = "multipleChoice")]
+    pub multiple_choice: bool,
+    pub deadline: Option<u64>,
+    pub closed: bool,
+    /// Number of votes of each option.
+    pub votes: Vec<u32>,
+    /// Number of members that voted.
+    pub voters: u32,
+    /// Options the viewer voted for.
+    #[serde(rename = "myVotes")]
+    pub my_votes: Vec<u8>,
+}
+
+#[derive(
+    BorshDeserialize,
+    BorshSerialize,
+    Serialize,
+    Deserialize,
+    JsonSchema,
+    Clone,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Debug,
+    Default,
+)]
+#[serde(crate = "near_sdk::serde", tag = "type", rename_all = "camelCase")]
+pub enum MessageFormat {
+    #[default]
+    Plain,
+    Markdown,
+    /// A code block, highlighted as `language` when set.
+    Code {
+        language: Option<String>,
+    },
+}
+
+/// Metadata of a link in the message text, fetched by the sending client so readers do not
+/// have to request the page.
+#[derive(
+    BorshDeserialize,
+    BorshSerialize,
+    Serialize,
+    Deserialize,
+    JsonSchema,
+    Clone,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Debug,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LinkPreview {
+    /// Link as it appears in the message text.
+    pub url: String,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(rename = "siteName", default)]
+    pub site_name: Option<String>,
+    /// Preview image, an `https://` or `ipfs://` URI.
+    #[serde(rename = "imageUri", default)]
+    pub image_uri: Option<String>,
+}
+
+/// A file stored off-chain, described well enough for clients to fetch, verify and render it.
+#[derive(
+    BorshDeserialize,
+    BorshSerialize,
+    Serialize,
+    Deserialize,
+    JsonSchema,
+    Clone,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Debug,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Attachment {
+    /// Hash of the file content, e.g. hex SHA-256, for clients to verify what they fetched.
+    #[serde(rename = "contentHash")]
+    pub content_hash: String,
+    #[serde(rename = "mimeType")]
+    pub mime_type: String,
+    /// Size of the file in bytes.
+    pub size: u64,
+    pub filename: String,
+    /// Where the content is stored, such as `ipfs://<cid>` or a NEAR social path.
+    pub uri: String,
+    /// Hash of a thumbnail stored next to the content, for images and videos.
+    #[serde(rename = "thumbnailHash", default)]
+    pub thumbnail_hash: Option<String>,
+}
+
+/// A page of `get_messages_by_tag`, newest first.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TaggedMessages {
+    pub messages: Vec<MessageWithReactions>,
+    /// Cursor to pass as `before` for the next page, `None` on the last page.
+    pub next: Option<u64>,
+}
+
+/// A channel message as listed by `get_messages_received_after`.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReceivedMessage {
+    /// Top-level message of the thread this message replies in.
+    pub parent: Option<MessageId>,
+    pub message: Message,
+}
+
+/// Quote of a replied or forwarded message, its text cut to `PREVIEW_LENGTH` characters.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MessagePreview {
+    pub id: MessageId,
+    pub sender: AccountId,
+    pub timestamp: u64,
+    pub text: String,
+    pub deleted: bool,
 }
 
-#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, JsonSchema, Clone)]
 #[serde(crate = "near_sdk::serde")]
 pub struct MessageWithReactions {
     pub id: MessageId,
     pub text: String,
     pub timestamp: u64,
     pub sender: AccountId,
-    pub reactions: Option<HashMap<MessageId, Vec<AccountId>>>,
+    pub deleted: bool,
+    #[serde(rename = "expiresAt")]
+    pub expires_at: Option<u64>,
+    #[serde(rename = "receivedAt")]
+    pub received_at: u64,
+    pub attachments: Vec<Attachment>,
+    pub format: MessageFormat,
+    #[serde(rename = "linkPreviews")]
+    pub link_previews: Vec<LinkPreview>,
+    pub poll: Option<PollResults>,
+    pub reactions: Vec<ReactionSummary>,
+    /// `None` as well when the referenced message is gone, e.g. its channel was deleted.
+    #[serde(rename = "replyTo")]
+    pub reply_to: Option<MessagePreview>,
+    #[serde(rename = "forwardedFrom")]
+    pub forwarded_from: Option<MessagePreview>,
 }
 
-#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, JsonSchema, Clone)]
 #[serde(crate = "near_sdk::serde")]
 pub struct MessageWithReactionsAndThread {
     pub id: MessageId,
     pub text: String,
     pub timestamp: u64,
     pub sender: AccountId,
-    pub reactions: Option<HashMap<MessageId, Vec<AccountId>>>,
+    pub deleted: bool,
+    #[serde(rename = "expiresAt")]
+    pub expires_at: Option<u64>,
+    #[serde(rename = "receivedAt")]
+    pub received_at: u64,
+    pub attachments: Vec<Attachment>,
+    pub format: MessageFormat,
+    #[serde(rename = "linkPreviews")]
+    pub link_previews: Vec<LinkPreview>,
+    pub poll: Option<PollResults>,
+    pub reactions: Vec<ReactionSummary>,
+    #[serde(rename = "replyTo")]
+    pub reply_to: Option<MessagePreview>,
+    #[serde(rename = "forwardedFrom")]
+    pub forwarded_from: Option<MessagePreview>,
     pub thread: Vec<MessageWithReactions>,
 }
 
-#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+/// Number of accounts that reacted to a message with `reaction`.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReactionCount {
+    pub reaction: String,
+    pub count: u32,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReactionSummary {
+    pub reaction: String,
+    pub count: u32,
+    /// Whether the `viewer` passed to `get_messages` is one of the accounts that reacted.
+    #[serde(rename = "reactedByMe")]
+    pub reacted_by_me: bool,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, JsonSchema)]
 #[serde(crate = "near_sdk::serde")]
 pub struct UnreadMessage {
     pub count: usize,
@@ -86,21 +454,323 @@ pub struct UnreadMessage {
     pub last_seen: Option<MessageId>,
 }
 
-#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, JsonSchema)]
 #[serde(crate = "near_sdk::serde")]
 pub struct UnreadMessageInfo {
     pub channels: HashMap<String, UnreadMessage>,
     pub chats: HashMap<AccountId, UnreadMessage>,
+    /// Threads the account has replied in or read, keyed by parent message.
     pub threads: HashMap<MessageId, UnreadMessage>,
 }
 
-#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, JsonSchema, Clone)]
 #[serde(crate = "near_sdk::serde")]
 pub struct ChannelMetadata {
     #[serde(rename = "createdAt")]
     pub created_at: u64,
     #[serde(rename = "createdBy")]
     pub created_by: AccountId,
+    /// Milliseconds after which new messages disappear unless they expire sooner.
+    #[serde(rename = "messageExpiry", default)]
+    pub message_expiry: Option<u64>,
+    #[serde(default)]
+    pub retention: RetentionPolicy,
+}
+
+/// Limits on the history a channel keeps, enforced by `prune`.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, JsonSchema, Clone, Default, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RetentionPolicy {
+    /// Number of most recent messages kept.
+    #[serde(rename = "maxMessages", default)]
+    pub max_messages: Option<u32>,
+    /// Milliseconds, counted from the block time a message was received at, messages are kept for.
+    #[serde(rename = "maxAgeMs", default)]
+    pub max_age_ms: Option<u64>,
+}
+
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum RegistrationMode {
+    Open,
+    Closed,
+    /// Only accounts added with `add_to_allowlist` may join.
+    Allowlist,
+    /// Joining requires a one-time code created for the joining account with `create_invite`.
+    Invite,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+struct InviteInfo {
+    pub created_by: AccountId,
+    pub created_at: u64,
+}
+
+/// Per-account limits, `0` disables the corresponding limit.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RateLimits {
+    #[serde(rename = "messagesPerMinute")]
+    pub messages_per_minute: u32,
+    #[serde(rename = "reactionsPerMinute")]
+    pub reactions_per_minute: u32,
+    #[serde(rename = "channelsPerDay")]
+    pub channels_per_day: u32,
+}
+
+/// Remaining actions an account may currently perform, `None` when unlimited.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RateLimitBudget {
+    pub messages: Option<u32>,
+    pub reactions: Option<u32>,
+    pub channels: Option<u32>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Clone, Copy)]
+struct TokenBucket {
+    pub tokens: u32,
+    pub updated_at: u64,
+}
+
+impl TokenBucket {
+    fn full(capacity: u32, now: u64) -> Self {
+        Self {
+            tokens: capacity,
+            updated_at: now,
+        }
+    }
+
+    /// Adds the tokens refilled since the last update, `capacity` tokens per `period_ms`.
+    fn refill(&mut self, capacity: u32, period_ms: u64, now: u64) {
+        let elapsed = now.saturating_sub(self.updated_at);
+        let refilled = elapsed.saturating_mul(capacity as u64) / period_ms;
+        if refilled == 0 {
+            self.tokens = std::cmp::min(self.tokens, capacity);
+            return;
+        }
+        let tokens = (self.tokens as u64).saturating_add(refilled);
+        if tokens >= capacity as u64 {
+            self.tokens = capacity;
+            self.updated_at = now;
+        } else {
+            self.tokens = tokens as u32;
+            self.updated_at += refilled * period_ms / capacity as u64;
+        }
+    }
+
+    fn available(&self, capacity: u32, period_ms: u64, now: u64) -> u32 {
+        let mut bucket = *self;
+        bucket.refill(capacity, period_ms, now);
+        bucket.tokens
+    }
+
+    fn take(&mut self, capacity: u32, period_ms: u64, now: u64) -> bool {
+        self.refill(capacity, period_ms, now);
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+}
+
+#[derive(Clone, Copy)]
+enum RateLimited {
+    Message,
+    Reaction,
+    Channel,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+struct RateLimitState {
+    pub messages: TokenBucket,
+    pub reactions: TokenBucket,
+    pub channels: TokenBucket,
+}
+
+impl RateLimitState {
+    fn full(limits: &RateLimits, now: u64) -> Self {
+        Self {
+            messages: TokenBucket::full(limits.messages_per_minute, now),
+            reactions: TokenBucket::full(limits.reactions_per_minute, now),
+            channels: TokenBucket::full(limits.channels_per_day, now),
+        }
+    }
+
+    fn bucket(&self, action: RateLimited) -> &TokenBucket {
+        match action {
+            RateLimited::Message => &self.messages,
+            RateLimited::Reaction => &self.reactions,
+            RateLimited::Channel => &self.channels,
+        }
+    }
+
+    fn bucket_mut(&mut self, action: RateLimited) -> &mut TokenBucket {
+        match action {
+            RateLimited::Message => &mut self.messages,
+            RateLimited::Reaction => &mut self.reactions,
+            RateLimited::Channel => &mut self.channels,
+        }
+    }
+}
+
+impl RateLimits {
+    fn limit(&self, action: RateLimited) -> (u32, u64) {
+        match action {
+            RateLimited::Message => (self.messages_per_minute, MINUTE_MS),
+            RateLimited::Reaction => (self.reactions_per_minute, MINUTE_MS),
+            RateLimited::Channel => (self.channels_per_day, DAY_MS),
+        }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Config {
+    #[serde(rename = "defaultChannel")]
+    pub default_channel: Channel,
+    /// Members seen within this many milliseconds are online, or active.
+    #[serde(rename = "activeMsThreshold")]
+    pub active_ms_threshold: u64,
+    /// Members not seen within this many milliseconds are offline, whatever their status.
+    #[serde(rename = "awayMsThreshold")]
+    pub away_ms_threshold: u64,
+    /// Maximum length of a message text in bytes.
+    #[serde(rename = "maxMessageLength")]
+    pub max_message_length: u32,
+    /// Maximum length of a channel name in characters.
+    #[serde(rename = "maxChannelNameLength")]
+    pub max_channel_name_length: u32,
+    /// Maximum number of user-perceived characters in a reaction.
+    #[serde(rename = "maxReactionGraphemes")]
+    pub max_reaction_graphemes: u32,
+    /// Reactions members may use, any reaction is accepted when unset.
+    #[serde(rename = "allowedReactions")]
+    pub allowed_reactions: Option<Vec<String>>,
+    /// Maximum number of different reactions on a single message.
+    #[serde(rename = "maxReactionsPerMessage")]
+    pub max_reactions_per_message: u32,
+    #[serde(rename = "maxAttachmentsPerMessage")]
+    pub max_attachments_per_message: u32,
+    /// Maximum declared size of an attachment in bytes.
+    #[serde(rename = "maxAttachmentSize")]
+    pub max_attachment_size: u64,
+    #[serde(rename = "maxLinkPreviewsPerMessage")]
+    pub max_link_previews_per_message: u32,
+    /// Maximum number of options of a poll, at most 256.
+    #[serde(rename = "maxPollOptions")]
+    pub max_poll_options: u32,
+    /// Whether channel messages are indexed by their hashtags for `get_messages_by_tag`.
+    #[serde(rename = "tagIndex")]
+    pub tag_index: bool,
+    /// Most recent messages kept in the index per tag and channel.
+    #[serde(rename = "maxMessagesPerTag")]
+    pub max_messages_per_tag: u32,
+    /// Distinct tags indexed per channel, further tags are not indexed until others are emptied.
+    #[serde(rename = "maxTagsPerChannel")]
+    pub max_tags_per_channel: u32,
+    pub registration: RegistrationMode,
+    /// Number of invite codes a member other than the owner may create.
+    #[serde(rename = "inviteQuota")]
+    pub invite_quota: u32,
+    #[serde(rename = "rateLimits")]
+    pub rate_limits: RateLimits,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            default_channel: Channel {
+                name: "general".to_string(),
+            },
+            active_ms_threshold: ACTIVE_MS_THRESHOLD,
+            away_ms_threshold: AWAY_MS_THRESHOLD,
+            max_message_length: MAX_MESSAGE_LENGTH,
+            max_channel_name_length: MAX_CHANNEL_NAME_LENGTH,
+            max_reaction_graphemes: MAX_REACTION_GRAPHEMES,
+            allowed_reactions: None,
+            max_reactions_per_message: MAX_REACTIONS_PER_MESSAGE,
+            max_attachments_per_message: MAX_ATTACHMENTS_PER_MESSAGE,
+            max_attachment_size: MAX_ATTACHMENT_SIZE,
+            max_link_previews_per_message: MAX_LINK_PREVIEWS_PER_MESSAGE,
+            max_poll_options: MAX_POLL_OPTIONS,
+            tag_index: false,
+            max_messages_per_tag: MAX_MESSAGES_PER_TAG,
+            max_tags_per_channel: MAX_TAGS_PER_CHANNEL,
+            registration: RegistrationMode::Open,
+            invite_quota: INVITE_QUOTA,
+            rate_limits: RateLimits {
+                messages_per_minute: MESSAGES_PER_MINUTE,
+                reactions_per_minute: REACTIONS_PER_MINUTE,
+                channels_per_day: CHANNELS_PER_DAY,
+            },
+        }
+    }
+}
+
+#[derive(
+    BorshDeserialize,
+    BorshSerialize,
+    Serialize,
+    Deserialize,
+    JsonSchema,
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum ModerationAction {
+    Kick,
+    Mute { until: u64 },
+    Unmute,
+    Ban,
+    Unban,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ModerationEntry {
+    pub action: ModerationAction,
+    pub account: AccountId,
+    /// Channel the action applies to, `None` for the whole instance.
+    pub group: Option<Channel>,
+    pub moderator: AccountId,
+    pub reason: Option<String>,
+    pub timestamp: u64,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum MessageLocation {
+    Channel(Channel),
+    Chat(AccountId, AccountId),
+}
+
+/// Last message an account read in a channel or chat, with the number of messages up to and
+/// including it when it was read.
+#[derive(BorshDeserialize, BorshSerialize)]
+struct ReadMarker {
+    pub message_id: MessageId,
+    pub count: u64,
+}
+
+/// Last reply an account read in a thread, by the time it was sent so replies removed since do
+/// not shift the unread count.
+#[derive(BorshDeserialize, BorshSerialize)]
+struct ThreadMarker {
+    pub message_id: MessageId,
+    pub timestamp: u64,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+struct PollTally {
+    votes: Vec<u32>,
+    ballots: UnorderedMap<AccountId, Vec<u8>>,
 }
 
 #[derive(BorshDeserialize, BorshSerialize)]
@@ -115,44 +785,123 @@ struct ChannelInfo {
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct Curb {
     name: String,
+    owner: AccountId,
+    pending_owner: Option<AccountId>,
+    config: Config,
 
     created_at: u64,
     members: UnorderedMap<AccountId, u64>,
     member_keys: UnorderedMap<AccountId, PublicKey>,
+    statuses: LookupMap<AccountId, Status>,
 
     channels: UnorderedMap<Channel, ChannelInfo>,
     channel_members: UnorderedMap<Channel, UnorderedSet<AccountId>>,
     member_channels: UnorderedMap<AccountId, UnorderedSet<Channel>>,
 
     chats: UnorderedMap<(AccountId, AccountId), ChannelInfo>,
+    member_chats: LookupMap<AccountId, UnorderedSet<AccountId>>,
+
+    message_counts: LookupMap<MessageLocation, u64>,
+    read_markers: LookupMap<(AccountId, MessageLocation), ReadMarker>,
 
     threads: UnorderedMap<MessageId, Vec<Message>>,
+    /// Top-level messages with a thread per conversation, so expired replies are found without
+    /// looking up the thread of every message.
+    thread_parents: LookupMap<MessageLocation, Vec<MessageId>>,
+    /// Parent of each thread reply.
+    reply_parents: LookupMap<MessageId, MessageId>,
+    /// Threads each account has read, keyed by parent.
+    thread_markers: LookupMap<AccountId, UnorderedMap<MessageId, ThreadMarker>>,
 
-    reactions: UnorderedMap<MessageId, UnorderedMap<String, UnorderedSet<AccountId>>>,
+    reactions: LookupMap<MessageId, Vec<ReactionCount>>,
+    reactors: UnorderedMap<(MessageId, String), UnorderedSet<AccountId>>,
+    /// Reactions per account, so `leave` withdraws them without scanning every reactor set.
+    account_reactions: LookupMap<AccountId, UnorderedSet<(MessageId, String)>>,
+
+    poll_tallies: LookupMap<MessageId, PollTally>,
+    /// Polls each account has a ballot in.
+    account_ballots: LookupMap<AccountId, UnorderedSet<MessageId>>,
+    /// Conversations each account has sent messages to, which `leave` tombstones.
+    sent_in: LookupMap<AccountId, Vec<MessageLocation>>,
+
+    /// Ids of the latest top-level messages per hashtag, oldest first, each with its cursor for
+    /// `get_messages_by_tag`.
+    channel_tags: LookupMap<Channel, UnorderedMap<String, Vec<(u64, MessageId)>>>,
+    /// `(receivedAt, id)` of every message of a channel, top-level and replies, in the order they
+    /// were received, for `get_messages_received_after`. Entries are only appended, those of
+    /// removed messages are skipped when read.
+    received_order: LookupMap<Channel, Vector<(u64, MessageId)>>,
+
+    message_locations: LookupMap<MessageId, MessageLocation>,
+    /// Timestamps of all messages, to binary search top-level messages by id.
+    message_timestamps: LookupMap<MessageId, u64>,
+
+    banned: UnorderedSet<AccountId>,
+    channel_bans: UnorderedMap<Channel, UnorderedSet<AccountId>>,
+    channel_mutes: UnorderedMap<Channel, UnorderedMap<AccountId, u64>>,
+    moderation_log: Vector<ModerationEntry>,
+
+    allowlist: UnorderedSet<AccountId>,
+    invites: UnorderedMap<String, InviteInfo>,
+    invites_created: UnorderedMap<AccountId, u32>,
+
+    storage_deposits: UnorderedMap<AccountId, Balance>,
+
+    rate_limits: LookupMap<AccountId, RateLimitState>,
 }
 
 #[near_bindgen]
 impl Curb {
+    /// Initializes (or re-initializes, dropping the previous state) the instance. Only the
+    /// contract account itself may call it, so it has to be batched with the deployment, and
+    /// `owner` is required as the contract account can not sign the owner's calls.
     #[init(ignore_state)]
-    pub fn new(name: String) -> Self {
+    #[private]
+    pub fn new(name: String, owner: AccountId) -> Self {
         Self {
             name,
+            owner,
+            pending_owner: None,
+            config: Config::default(),
             created_at: env::block_timestamp_ms(),
             members: UnorderedMap::new(b"m".to_vec()),
             member_keys: UnorderedMap::new(b"k".to_vec()),
+            statuses: LookupMap::new(b"f".to_vec()),
             channels: UnorderedMap::new(b"n".to_vec()),
             channel_members: UnorderedMap::new(b"c".to_vec()),
             member_channels: UnorderedMap::new(b"e".to_vec()),
             chats: UnorderedMap::new(b"t".to_vec()),
+            member_chats: LookupMap::new(b"p".to_vec()),
+            message_counts: LookupMap::new(b"g".to_vec()),
+            read_markers: LookupMap::new(b"v".to_vec()),
             threads: UnorderedMap::new(b"h".to_vec()),
-            reactions: UnorderedMap::new(b"r".to_vec()),
+            thread_parents: LookupMap::new(b"T".to_vec()),
+            reply_parents: LookupMap::new(b"P".to_vec()),
+            thread_markers: LookupMap::new(b"F".to_vec()),
+            reactions: LookupMap::new(b"r".to_vec()),
+            reactors: UnorderedMap::new(b"y".to_vec()),
+            account_reactions: LookupMap::new(b"R".to_vec()),
+            poll_tallies: LookupMap::new(b"j".to_vec()),
+            account_ballots: LookupMap::new(b"B".to_vec()),
+            sent_in: LookupMap::new(b"S".to_vec()),
+            channel_tags: LookupMap::new(b"z".to_vec()),
+            received_order: LookupMap::new(b"O".to_vec()),
+            message_locations: LookupMap::new(b"l".to_vec()),
+            message_timestamps: LookupMap::new(b"s".to_vec()),
+            banned: UnorderedSet::new(b"b".to_vec()),
+            channel_bans: UnorderedMap::new(b"a".to_vec()),
+            channel_mutes: UnorderedMap::new(b"u".to_vec()),
+            moderation_log: Vector::new(b"o".to_vec()),
+            allowlist: UnorderedSet::new(b"w".to_vec()),
+            invites: UnorderedMap::new(b"i".to_vec()),
+            invites_created: UnorderedMap::new(b"q".to_vec()),
+            storage_deposits: UnorderedMap::new(b"d".to_vec()),
+            rate_limits: LookupMap::new(b"x".to_vec()),
         }
     }
 
-    fn default_channel() -> Channel {
-        Channel {
-            name: "general".to_string(),
-        }
+    fn default_channel(&self) -> Channel {
+        self.config.default_channel.clone()
     }
 
     fn register_activity(&mut self) {
@@ -161,29 +910,309 @@ impl Curb {
         // TODO support multiple keys
         self.member_keys
             .insert(env::predecessor_account_id(), env::signer_account_pk());
+        if env::attached_deposit() > 0 {
+            *self
+                .storage_deposits
+                .entry(env::predecessor_account_id())
+                .or_insert(0) += env::attached_deposit();
+        }
     }
 
     #[payable]
     pub fn join(&mut self) {
         // TODO handle storage payments
-        require!(
-            !self.members.contains_key(&env::predecessor_account_id()),
-            "Already a member"
+        self.internal_join(None);
+    }
+
+    #[payable]
+    pub fn join_with_invite(&mut self, code: String) {
+        // TODO handle storage payments
+        self.internal_join(Some(code));
+    }
+
+    fn internal_join(&mut self, invite_code: Option<String>) {
+        let account = env::predecessor_account_id();
+        ensure(
+            !self.members.contains_key(&account),
+            CurbError::AlreadyAMember,
         );
+        ensure(!self.banned.contains(&account), CurbError::AccountBanned);
+        if account != self.owner {
+            match self.config.registration {
+                RegistrationMode::Open => {}
+                RegistrationMode::Closed => CurbError::RegistrationClosed.panic(),
+                RegistrationMode::Allowlist => {
+                    ensure(self.allowlist.contains(&account), CurbError::NotOnAllowlist)
+                }
+                RegistrationMode::Invite => {
+                    let code = invite_code.unwrap_or_else(|| CurbError::InviteCodeRequired.panic());
+                    let code_hash = Curb::invite_code_hash(&account, &code);
+                    ensure(
+                        self.invites.remove(&code_hash).is_some(),
+                        CurbError::InvalidInviteCode,
+                    );
+                }
+            }
+        }
         if self.members.is_empty() {
-            self.internal_create_group(Curb::default_channel(), false);
+            self.internal_create_group(self.default_channel(), false);
         }
         self.register_activity();
         self.member_channels.insert(
             env::predecessor_account_id(),
             UnorderedSet::new(env::predecessor_account_id().as_bytes()),
         );
-        self.join_group(Curb::default_channel());
+        self.join_group(self.default_channel());
     }
 
-    #[payable]
-    pub fn ping(&mut self) {
-        self.register_activity();
+    /// Removes the caller and all of their membership, read and reaction data from the instance,
+    /// optionally tombstoning the messages they sent, and refunds their storage deposit up to the
+    /// cost of the storage released.
+    pub fn leave(&mut self, tombstone_messages: bool) {
+        let account = env::predecessor_account_id();
+        ensure(self.members.contains_key(&account), CurbError::NotAMember);
+        self.remove_account(&account, tombstone_messages);
+    }
+
+    /// Removes `account` from its channels and chats and withdraws its reactions and ballots,
+    /// walking only the indexes kept per account, then refunds its storage deposit up to the
+    /// cost of the storage released.
+    fn remove_account(&mut self, account: &AccountId, tombstone_messages: bool) {
+        let storage_before = env::storage_usage();
+
+        if let Some(channels) = self.member_channels.get(account) {
+            let channels: Vec<Channel> = channels.iter().cloned().collect();
+            for channel in channels.iter() {
+                self.internal_leave_group(channel, account);
+                if let Some(members) = self.channel_members.get_mut(channel) {
+                    members.flush();
+                }
+            }
+        }
+        if let Some(mut channels) = self.member_channels.remove(account) {
+            channels.clear();
+        }
+        if let Some(mut partners) = self.member_chats.remove(account) {
+            for partner in partners.iter() {
+                let key = Curb::order_accounts(account.clone(), partner.clone());
+                self.clear_read_marker(account, MessageLocation::Chat(key.0, key.1));
+            }
+            partners.clear();
+        }
+        self.members.remove(account);
+        self.member_keys.remove(account);
+        self.statuses.remove(account);
+        self.invites_created.remove(account);
+        self.rate_limits.remove(account);
+
+        if let Some(mut markers) = self.thread_markers.remove(account) {
+            markers.clear();
+        }
+        let sent_in = self.sent_in.remove(account).unwrap_or_default();
+        if tombstone_messages {
+            for location in sent_in {
+                self.tombstone_messages(location, account);
+            }
+        }
+        if let Some(mut reactions) = self.account_reactions.remove(account) {
+            for (message_id, reaction) in reactions.iter() {
+                let key = (message_id.clone(), reaction.clone());
+                if let Some(reactors) = self.reactors.get_mut(&key) {
+                    reactors.remove(account);
+                    if reactors.is_empty() {
+                        self.reactors.remove(&key).unwrap().clear();
+                    } else {
+                        reactors.flush();
+                    }
+                }
+                Curb::uncount_reaction(&mut self.reactions, message_id, reaction);
+            }
+            reactions.clear();
+        }
+        if let Some(mut polls) = self.account_ballots.remove(account) {
+            for message_id in polls.iter() {
+                if let Some(tally) = self.poll_tallies.get_mut(message_id) {
+                    if let Some(ballot) = tally.ballots.remove(account) {
+                        for option in ballot {
+                            tally.votes[option as usize] -= 1;
+                        }
+                        tally.ballots.flush();
+                    }
+                }
+            }
+            polls.clear();
+        }
+
+        let deposit = self.storage_deposits.remove(account).unwrap_or(0);
+        self.flush();
+        let released = storage_before.saturating_sub(env::storage_usage());
+        let refund = std::cmp::min(deposit, released as Balance * env::storage_byte_cost());
+        if refund > 0 {
+            Promise::new(account.clone()).transfer(refund);
+        }
+    }
+
+    /// Drops `account`'s read marker in `location`.
+    fn clear_read_marker(&mut self, account: &AccountId, location: MessageLocation) {
+        let info = match &location {
+            MessageLocation::Channel(channel) => self.channels.get_mut(channel),
+            MessageLocation::Chat(account1, account2) => {
+                self.chats.get_mut(&(account1.clone(), account2.clone()))
+            }
+        };
+        if let Some(info) = info {
+            info.last_read.remove(account);
+            info.last_read.flush();
+        }
+        self.read_markers.remove(&(account.clone(), location));
+    }
+
+    /// Writes cached collection changes so `env::storage_usage` reflects them.
+    fn flush(&mut self) {
+        self.members.flush();
+        self.member_keys.flush();
+        self.statuses.flush();
+        self.channels.flush();
+        self.channel_members.flush();
+        self.member_channels.flush();
+        self.chats.flush();
+        self.member_chats.flush();
+        self.message_counts.flush();
+        self.read_markers.flush();
+        self.threads.flush();
+        self.thread_parents.flush();
+        self.reply_parents.flush();
+        self.thread_markers.flush();
+        self.reactions.flush();
+        self.reactors.flush();
+        self.account_reactions.flush();
+        self.poll_tallies.flush();
+        self.account_ballots.flush();
+        self.sent_in.flush();
+        self.channel_tags.flush();
+        self.received_order.flush();
+        self.channel_bans.flush();
+        self.channel_mutes.flush();
+        self.invites_created.flush();
+        self.storage_deposits.flush();
+        self.rate_limits.flush();
+    }
+
+    /// Clears the content of the messages `account` sent in `location` and its threads.
+    fn tombstone_messages(&mut self, location: MessageLocation, account: &AccountId) {
+        let info = match &location {
+            MessageLocation::Channel(channel) => self.channels.get_mut(channel),
+            MessageLocation::Chat(account1, account2) => {
+                self.chats.get_mut(&(account1.clone(), account2.clone()))
+            }
+        };
+        let Some(info) = info else {
+            return;
+        };
+        let tombstoned = Curb::tombstone(&mut info.messages, account);
+        if let MessageLocation::Channel(channel) = &location {
+            self.unindex_tags(channel, &tombstoned);
+        }
+        for message in tombstoned.iter().filter(|m| m.poll.is_some()) {
+            self.remove_poll(&message.id);
+        }
+        for parent in self
+            .thread_parents
+            .get(&location)
+            .cloned()
+            .unwrap_or_default()
+        {
+            if let Some(thread) = self.threads.get_mut(&parent) {
+                Curb::tombstone(thread, account);
+            }
+        }
+    }
+
+    /// Clears the content of `account`'s messages among `messages`, returning them as they were.
+    fn tombstone(messages: &mut [Message], account: &AccountId) -> Vec<Message> {
+        let mut tombstoned = vec![];
+        for message in messages.iter_mut().filter(|m| &m.sender == account) {
+            tombstoned.push(message.clone());
+            message.text.clear();
+            message.attachments.clear();
+            message.link_previews.clear();
+            message.format = MessageFormat::Plain;
+            message.poll = None;
+            message.deleted = true;
+        }
+        tombstoned
+    }
+
+    #[payable]
+    pub fn ping(&mut self) {
+        self.register_activity();
+    }
+
+    /// Sets the presence and custom status text others see for the caller until `expires_at`,
+    /// after which presence follows activity again. `Online` without a text clears the status.
+    #[payable]
+    pub fn set_status(
+        &mut self,
+        presence: Presence,
+        text: Option<String>,
+        expires_at: Option<u64>,
+    ) {
+        let account = env::predecessor_account_id();
+        ensure(self.members.contains_key(&account), CurbError::NotAMember);
+        let text = text.map(|text| validation::status_text(&text).unwrap_or_else(|e| e.panic()));
+        ensure(
+            expires_at.is_none_or(|expires_at| expires_at > env::block_timestamp_ms()),
+            CurbError::InvalidExpiry,
+        );
+        if presence == Presence::Online && text.is_none() {
+            self.statuses.remove(&account);
+        } else {
+            self.statuses.insert(
+                account,
+                Status {
+                    presence,
+                    text,
+                    expires_at,
+                },
+            );
+        }
+        self.register_activity();
+    }
+
+    fn consume_rate_limit(&mut self, action: RateLimited) {
+        let (capacity, period_ms) = self.config.rate_limits.limit(action);
+        if capacity == 0 {
+            return;
+        }
+        let now = env::block_timestamp_ms();
+        let limits = &self.config.rate_limits;
+        let allowed = self
+            .rate_limits
+            .entry(env::predecessor_account_id())
+            .or_insert_with(|| RateLimitState::full(limits, now))
+            .bucket_mut(action)
+            .take(capacity, period_ms, now);
+        ensure(allowed, CurbError::RateLimitExceeded);
+    }
+
+    pub fn get_rate_limit_budget(&self, account: AccountId) -> RateLimitBudget {
+        let now = env::block_timestamp_ms();
+        let state = self.rate_limits.get(&account);
+        let available = |action: RateLimited| {
+            let (capacity, period_ms) = self.config.rate_limits.limit(action);
+            if capacity == 0 {
+                return None;
+            }
+            Some(match state {
+                Some(state) => state.bucket(action).available(capacity, period_ms, now),
+                None => capacity,
+            })
+        };
+        RateLimitBudget {
+            messages: available(RateLimited::Message),
+            reactions: available(RateLimited::Reaction),
+            channels: available(RateLimited::Channel),
+        }
     }
 
     fn order_accounts(account: AccountId, other_account: AccountId) -> (AccountId, AccountId) {
@@ -204,17 +1233,29 @@ impl Curb {
 
     #[payable]
     pub fn create_group(&mut self, group: Channel) {
+        let group = group.normalized();
         // TODO handle storage payments
+        ensure(
+            self.members.contains_key(&env::predecessor_account_id()),
+            CurbError::NotAMember,
+        );
+        self.consume_rate_limit(RateLimited::Channel);
         self.internal_create_group(group, true);
         self.register_activity();
     }
 
     fn internal_create_group(&mut self, group: Channel, membership_required: bool) {
-        require!(group.name.len() > 0, "Group name too short!");
-        require!(!self.channels.contains_key(&group), "Group already exists");
-        require!(
+        let group = Channel {
+            name: validation::channel_name(&group.name, self.config.max_channel_name_length)
+                .unwrap_or_else(|e| e.panic()),
+        };
+        ensure(
+            !self.channels.contains_key(&group),
+            CurbError::GroupAlreadyExists,
+        );
+        ensure(
             !membership_required || self.members.contains_key(&env::predecessor_account_id()),
-            "Not a member"
+            CurbError::NotAMember,
         );
         self.channels.insert(
             group.clone(),
@@ -224,6 +1265,8 @@ impl Curb {
                 meta: ChannelMetadata {
                     created_at: env::block_timestamp_ms(),
                     created_by: env::predecessor_account_id(),
+                    message_expiry: None,
+                    retention: RetentionPolicy::default(),
                 },
                 last_read: UnorderedMap::new(env::sha256(group.name.as_bytes())),
             },
@@ -237,11 +1280,19 @@ impl Curb {
 
     #[payable]
     pub fn join_group(&mut self, group: Channel) {
+        let group = group.normalized();
         // TODO handle storage payments
-        require!(self.channels.contains_key(&group), "Group does not exist");
-        require!(
+        ensure(
+            self.channels.contains_key(&group),
+            CurbError::GroupDoesNotExist,
+        );
+        ensure(
             self.members.contains_key(&env::predecessor_account_id()),
-            "Not a member"
+            CurbError::NotAMember,
+        );
+        ensure(
+            !self.is_banned_from(&group, &env::predecessor_account_id()),
+            CurbError::BannedFromGroup,
         );
         self.channel_members
             .get_mut(&group)
@@ -258,36 +1309,79 @@ impl Curb {
 
     #[payable]
     pub fn leave_group(&mut self, group: Channel) {
+        let group = group.normalized();
         // TODO handle storage payments
-        require!(self.channels.contains_key(&group), "Group does not exist");
-        require!(
+        ensure(
+            self.channels.contains_key(&group),
+            CurbError::GroupDoesNotExist,
+        );
+        ensure(
             self.members.contains_key(&env::predecessor_account_id()),
-            "Not a member"
+            CurbError::NotAMember,
         );
-        self.channel_members
-            .get_mut(&group)
-            .unwrap()
-            .remove(&env::predecessor_account_id());
-        self.member_channels
-            .get_mut(&env::predecessor_account_id())
-            .unwrap()
-            .remove(&group);
-
-        if self.channel_members.get(&group).unwrap().is_empty() && group != Curb::default_channel()
-        {
-            self.channel_members.remove(&group);
-            self.channels.remove(&group);
-        }
+        self.internal_leave_group(&group, &env::predecessor_account_id());
         self.register_activity();
 
         env::value_return(&serde_json::to_vec(&group).unwrap());
     }
 
+    fn internal_leave_group(&mut self, group: &Channel, account: &AccountId) {
+        self.clear_read_marker(account, MessageLocation::Channel(group.clone()));
+        self.channel_members.get_mut(group).unwrap().remove(account);
+        self.member_channels.get_mut(account).unwrap().remove(group);
+
+        if self.channel_members.get(group).unwrap().is_empty()
+            && group != &self.config.default_channel
+        {
+            self.channel_members.remove(group);
+            // `last_read` is keyed by the channel name, clear it so a channel recreated under the
+            // same name does not inherit read markers pointing at the deleted messages.
+            if let Some(mut info) = self.channels.remove(group) {
+                let location = MessageLocation::Channel(group.clone());
+                for account in info.last_read.keys() {
+                    self.read_markers
+                        .remove(&(account.clone(), location.clone()));
+                }
+                self.message_counts.remove(&location);
+                let mut messages = std::mem::take(&mut info.messages);
+                for parent in self.thread_parents.remove(&location).unwrap_or_default() {
+                    messages.extend(self.threads.remove(&parent).unwrap_or_default());
+                }
+                self.release_storage(&messages);
+                info.last_read.clear();
+            }
+            if let Some(mut bans) = self.channel_bans.remove(group) {
+                bans.clear();
+            }
+            if let Some(mut mutes) = self.channel_mutes.remove(group) {
+                mutes.clear();
+            }
+            if let Some(mut tags) = self.channel_tags.remove(group) {
+                tags.clear();
+            }
+            if let Some(mut received) = self.received_order.remove(group) {
+                received.clear();
+            }
+        }
+    }
+
     #[payable]
     pub fn group_invite(&mut self, group: Channel, account: AccountId) {
+        let group = group.normalized();
         // TODO handle storage payments
-        require!(self.channels.contains_key(&group), "Group does not exist");
-        require!(self.members.contains_key(&account), "Not a member");
+        ensure(
+            self.channels.contains_key(&group),
+            CurbError::GroupDoesNotExist,
+        );
+        ensure(
+            self.members.contains_key(&env::predecessor_account_id()),
+            CurbError::NotAMember,
+        );
+        ensure(self.members.contains_key(&account), CurbError::NotAMember);
+        ensure(
+            !self.is_banned_from(&group, &account),
+            CurbError::BannedFromGroup,
+        );
         self.channel_members
             .get_mut(&group)
             .unwrap()
@@ -301,6 +1395,178 @@ impl Curb {
         env::value_return(&serde_json::to_vec(&group).unwrap());
     }
 
+    fn is_banned_from(&self, group: &Channel, account: &AccountId) -> bool {
+        match self.channel_bans.get(group) {
+            Some(bans) => bans.contains(account),
+            None => false,
+        }
+    }
+
+    fn is_muted_in(&self, group: &Channel, account: &AccountId) -> bool {
+        match self.channel_mutes.get(group).and_then(|m| m.get(account)) {
+            Some(until) => *until > env::block_timestamp_ms(),
+            None => false,
+        }
+    }
+
+    fn assert_instance_moderator(&self) {
+        ensure(
+            env::predecessor_account_id() == self.owner,
+            CurbError::NotAModerator,
+        );
+    }
+
+    fn assert_group_moderator(&self, group: &Channel) {
+        let moderator = env::predecessor_account_id();
+        let is_moderator = match self.channels.get(group) {
+            Some(info) => info.meta.created_by == moderator || moderator == self.owner,
+            None => CurbError::GroupDoesNotExist.panic(),
+        };
+        ensure(is_moderator, CurbError::NotAModerator);
+    }
+
+    fn log_moderation(
+        &mut self,
+        action: ModerationAction,
+        account: AccountId,
+        group: Option<Channel>,
+        reason: Option<String>,
+    ) {
+        self.moderation_log.push(ModerationEntry {
+            action,
+            account,
+            group,
+            moderator: env::predecessor_account_id(),
+            reason,
+            timestamp: env::block_timestamp_ms(),
+        });
+    }
+
+    #[payable]
+    pub fn kick(&mut self, group: Channel, account: AccountId, reason: Option<String>) {
+        let group = group.normalized();
+        self.assert_group_moderator(&group);
+        let is_member = match self.channel_members.get(&group) {
+            Some(cm) => cm.contains(&account),
+            None => false,
+        };
+        ensure(is_member, CurbError::NotAGroupMember);
+        self.internal_leave_group(&group, &account);
+        self.log_moderation(ModerationAction::Kick, account, Some(group.clone()), reason);
+
+        env::value_return(&serde_json::to_vec(&group).unwrap());
+    }
+
+    #[payable]
+    pub fn mute(
+        &mut self,
+        group: Channel,
+        account: AccountId,
+        duration_ms: u64,
+        reason: Option<String>,
+    ) {
+        let group = group.normalized();
+        self.assert_group_moderator(&group);
+        ensure(self.members.contains_key(&account), CurbError::NotAMember);
+        let until = env::block_timestamp_ms().saturating_add(duration_ms);
+        self.channel_mutes
+            .entry(group.clone())
+            .or_insert_with(|| {
+                UnorderedMap::new(env::sha256(format!("mutes:{}", group.name).as_bytes()))
+            })
+            .insert(account.clone(), until);
+        self.log_moderation(
+            ModerationAction::Mute { until },
+            account,
+            Some(group.clone()),
+            reason,
+        );
+
+        env::value_return(&serde_json::to_vec(&group).unwrap());
+    }
+
+    #[payable]
+    pub fn unmute(&mut self, group: Channel, account: AccountId, reason: Option<String>) {
+        let group = group.normalized();
+        self.assert_group_moderator(&group);
+        let removed = match self.channel_mutes.get_mut(&group) {
+            Some(mutes) => mutes.remove(&account).is_some(),
+            None => false,
+        };
+        ensure(removed, CurbError::NotMuted);
+        self.log_moderation(
+            ModerationAction::Unmute,
+            account,
+            Some(group.clone()),
+            reason,
+        );
+
+        env::value_return(&serde_json::to_vec(&group).unwrap());
+    }
+
+    #[payable]
+    pub fn ban(&mut self, group: Channel, account: AccountId, reason: Option<String>) {
+        let group = group.normalized();
+        self.assert_group_moderator(&group);
+        ensure(
+            !self.is_banned_from(&group, &account),
+            CurbError::AlreadyBanned,
+        );
+        self.channel_bans
+            .entry(group.clone())
+            .or_insert_with(|| {
+                UnorderedSet::new(env::sha256(format!("bans:{}", group.name).as_bytes()))
+            })
+            .insert(account.clone());
+        let is_member = match self.channel_members.get(&group) {
+            Some(cm) => cm.contains(&account),
+            None => false,
+        };
+        if is_member {
+            self.internal_leave_group(&group, &account);
+        }
+        self.log_moderation(ModerationAction::Ban, account, Some(group.clone()), reason);
+
+        env::value_return(&serde_json::to_vec(&group).unwrap());
+    }
+
+    #[payable]
+    pub fn unban(&mut self, group: Channel, account: AccountId, reason: Option<String>) {
+        let group = group.normalized();
+        self.assert_group_moderator(&group);
+        let removed = match self.channel_bans.get_mut(&group) {
+            Some(bans) => bans.remove(&account),
+            None => false,
+        };
+        ensure(removed, CurbError::NotBanned);
+        self.log_moderation(
+            ModerationAction::Unban,
+            account,
+            Some(group.clone()),
+            reason,
+        );
+
+        env::value_return(&serde_json::to_vec(&group).unwrap());
+    }
+
+    /// Removes `account` from the instance as `leave` would, keeping its messages, and prevents
+    /// it from joining again until `unban_member` is called.
+    #[payable]
+    pub fn ban_member(&mut self, account: AccountId, reason: Option<String>) {
+        self.assert_instance_moderator();
+        ensure(!self.banned.contains(&account), CurbError::AlreadyBanned);
+        self.remove_account(&account, false);
+        self.banned.insert(account.clone());
+        self.log_moderation(ModerationAction::Ban, account, None, reason);
+    }
+
+    #[payable]
+    pub fn unban_member(&mut self, account: AccountId, reason: Option<String>) {
+        self.assert_instance_moderator();
+        ensure(self.banned.remove(&account), CurbError::NotBanned);
+        self.log_moderation(ModerationAction::Unban, account, None, reason);
+    }
+
     fn get_message_id(
         account: &AccountId,
         other_account: &Option<AccountId>,
@@ -325,27 +1591,28 @@ impl Curb {
             .concat(),
         );
 
-        let mut s = MessageId::with_capacity(bytes.len() * 2);
+        Curb::to_hex(bytes)
+    }
+
+    /// Hex encoded sha256 of `"{account}:{code}"`, which binds an invite code to the account
+    /// allowed to redeem it.
+    fn invite_code_hash(account: &AccountId, code: &str) -> String {
+        Curb::to_hex(&env::sha256(format!("{}:{}", account, code).as_bytes()))
+    }
+
+    fn to_hex(bytes: &[u8]) -> String {
+        let mut s = String::with_capacity(bytes.len() * 2);
         for &b in bytes {
             write!(&mut s, "{:02x}", b).unwrap();
         }
         s
     }
 
-    fn find_message_pos(messages: &Vec<Message>, message_id: &MessageId) -> usize {
-        for (i, m) in messages.iter().enumerate().rev() {
-            if &m.id == message_id {
-                return i;
-            }
-        }
-
-        0
-    }
-
-    fn find_last_seen_pos(&self, channel_info: &ChannelInfo, account: &AccountId) -> usize {
-        match channel_info.last_read.get(&account) {
-            Some(message_id) => Curb::find_message_pos(&channel_info.messages, message_id) + 1,
-            _ => 0,
+    /// Number of messages up to and including `message_id`, which must be one of `messages`.
+    fn read_count(messages: &[Message], message_id: &MessageId) -> u64 {
+        match messages.iter().rposition(|m| &m.id == message_id) {
+            Some(pos) => pos as u64 + 1,
+            None => CurbError::MessageDoesNotExist.panic(),
         }
     }
 
@@ -357,12 +1624,61 @@ impl Curb {
         message: String,
         timestamp: u64,
         parent_message: Option<MessageId>,
+        options: Option<MessageOptions>,
     ) {
+        let group = group.map(Channel::normalized);
+        let MessageOptions {
+            reply_to,
+            forwarded_from,
+            attachments,
+            format,
+            link_previews,
+            poll,
+            expires_at,
+        } = options.unwrap_or_default();
         // TODO handle storage payments
-        require!(
+        ensure(
             self.members.contains_key(&env::predecessor_account_id()),
-            "Not a member"
+            CurbError::NotAMember,
+        );
+        ensure(
+            account.is_some() || group.is_some(),
+            CurbError::MissingTarget,
         );
+        let message = validation::message(&message, self.config.max_message_length)
+            .unwrap_or_else(|e| e.panic());
+        let attachments = validation::attachments(
+            attachments,
+            self.config.max_attachments_per_message,
+            self.config.max_attachment_size,
+        )
+        .unwrap_or_else(|e| e.panic());
+        let format = validation::format(format).unwrap_or_else(|e| e.panic());
+        let link_previews = validation::link_previews(
+            link_previews,
+            &message,
+            self.config.max_link_previews_per_message,
+        )
+        .unwrap_or_else(|e| e.panic());
+        let poll = poll.map(|poll| {
+            ensure(
+                account.is_none() && parent_message.is_none(),
+                CurbError::InvalidPoll,
+            );
+            validation::poll(
+                poll,
+                self.config.max_poll_options,
+                env::block_timestamp_ms(),
+            )
+            .unwrap_or_else(|e| e.panic())
+        });
+        let expires_at = self.expiry(&account, &group, expires_at);
+        let tags = match format {
+            MessageFormat::Code { .. } => vec![],
+            _ if self.config.tag_index => validation::tags(&message, MAX_TAGS_PER_MESSAGE),
+            _ => vec![],
+        };
+        self.consume_rate_limit(RateLimited::Message);
         self.register_activity();
         let message_id = Curb::get_message_id(
             &env::predecessor_account_id(),
@@ -375,20 +1691,44 @@ impl Curb {
             id: message_id.clone(),
             text: message,
             sender: env::predecessor_account_id(),
-            timestamp: timestamp,
+            timestamp,
+            deleted: false,
+            reply_to,
+            forwarded_from,
+            attachments,
+            format,
+            link_previews,
+            poll,
+            expires_at,
+            received_at: env::block_timestamp_ms(),
         };
         if let Some(other) = account {
-            require!(
+            ensure(
                 self.members.contains_key(&other),
-                "Other account is not a member"
+                CurbError::OtherAccountNotAMember,
             );
 
             let key = Curb::order_accounts(env::predecessor_account_id(), other.clone());
+            self.assert_references(
+                &message,
+                &parent_message,
+                &MessageLocation::Chat(key.0.clone(), key.1.clone()),
+            );
+            self.message_locations.insert(
+                message_id.clone(),
+                MessageLocation::Chat(key.0.clone(), key.1.clone()),
+            );
+            self.message_timestamps
+                .insert(message_id.clone(), timestamp);
+            self.add_sent_location(MessageLocation::Chat(key.0.clone(), key.1.clone()));
 
             if let Some(parent_id) = parent_message {
-                let container = self.threads.entry(parent_id).or_insert(vec![]);
-                let pos = container.binary_search(&message).unwrap_or_else(|e| e);
-                container.insert(pos, message);
+                self.add_reply(
+                    MessageLocation::Chat(key.0.clone(), key.1.clone()),
+                    parent_id.clone(),
+                    message,
+                );
+                self.read_reply(parent_id, message_id);
             } else {
                 let chat = self.chats.entry(key.clone()).or_insert(ChannelInfo {
                     messages: vec![],
@@ -396,6 +1736,8 @@ impl Curb {
                     meta: ChannelMetadata {
                         created_at: env::block_timestamp_ms(),
                         created_by: env::predecessor_account_id(),
+                        message_expiry: None,
+                        retention: RetentionPolicy::default(),
                     },
                     last_read: UnorderedMap::new(format!("{}#{}", key.0, key.1).as_bytes()),
                 });
@@ -404,36 +1746,134 @@ impl Curb {
                 let pos = messages.binary_search(&message).unwrap_or_else(|e| e);
                 messages.insert(pos, message);
 
+                self.add_chat_partners(&key.0, &key.1);
+                self.count_message(MessageLocation::Chat(key.0.clone(), key.1.clone()));
                 self.read_message(Some(other.clone()), None, message_id);
             }
 
             env::value_return(&serde_json::to_vec(&other).unwrap());
         } else if let Some(channel) = group {
-            require!(self.channels.contains_key(&channel), "Group does not exist");
+            ensure(
+                self.channels.contains_key(&channel),
+                CurbError::GroupDoesNotExist,
+            );
             let is_member = match self.channel_members.get(&channel) {
                 Some(cm) => cm.contains(&env::predecessor_account_id()),
                 None => false,
             };
-            require!(is_member, "Not a group member");
+            ensure(is_member, CurbError::NotAGroupMember);
+            ensure(
+                !self.is_muted_in(&channel, &env::predecessor_account_id()),
+                CurbError::MutedInGroup,
+            );
+            self.assert_references(
+                &message,
+                &parent_message,
+                &MessageLocation::Channel(channel.clone()),
+            );
+            self.message_locations.insert(
+                message_id.clone(),
+                MessageLocation::Channel(channel.clone()),
+            );
+            self.message_timestamps
+                .insert(message_id.clone(), timestamp);
+            self.add_sent_location(MessageLocation::Channel(channel.clone()));
+            self.add_received(&channel, &message_id);
             if let Some(parent_id) = parent_message {
-                let container = self.threads.entry(parent_id).or_insert(vec![]);
-                let pos = container.binary_search(&message).unwrap_or_else(|e| e);
-                container.insert(pos, message);
+                self.add_reply(
+                    MessageLocation::Channel(channel.clone()),
+                    parent_id.clone(),
+                    message,
+                );
+                self.read_reply(parent_id, message_id);
             } else {
                 let messages = &mut self.channels.get_mut(&channel).unwrap().messages;
 
                 let pos = messages.binary_search(&message).unwrap_or_else(|e| e);
                 messages.insert(pos, message);
 
+                self.index_tags(&channel, &message_id, tags);
+                self.count_message(MessageLocation::Channel(channel.clone()));
                 self.read_message(None, Some(channel.clone()), message_id);
             }
 
             env::value_return(&serde_json::to_vec(&channel).unwrap());
         } else {
-            panic!("Either account or group need to be provided");
+            CurbError::MissingTarget.panic();
+        }
+    }
+
+    /// Records that the caller has sent messages to `location`.
+    fn add_sent_location(&mut self, location: MessageLocation) {
+        let sent_in = self
+            .sent_in
+            .entry(env::predecessor_account_id())
+            .or_default();
+        if !sent_in.contains(&location) {
+            sent_in.push(location);
+        }
+    }
+
+    fn add_reply(&mut self, location: MessageLocation, parent_id: MessageId, message: Message) {
+        self.reply_parents
+            .insert(message.id.clone(), parent_id.clone());
+        let thread = self.threads.entry(parent_id.clone()).or_default();
+        if thread.is_empty() {
+            self.thread_parents
+                .entry(location)
+                .or_default()
+                .push(parent_id);
         }
+        let pos = thread.binary_search(&message).unwrap_or_else(|e| e);
+        thread.insert(pos, message);
     }
 
+    /// Appends message `message_id` of `channel` to its receive order, keeping the messages
+    /// received in the same block ordered by id so `(receivedAt, id)` cursors stay sorted.
+    fn add_received(&mut self, channel: &Channel, message_id: &MessageId) {
+        let received = self
+            .received_order
+            .entry(channel.clone())
+            .or_insert_with(|| {
+                Vector::new(env::sha256(
+                    format!("received-order:{}", channel.name).as_bytes(),
+                ))
+            });
+        let entry = (env::block_timestamp_ms(), message_id.clone());
+        let mut i = received.len();
+        received.push(entry.clone());
+        // Only messages received in the same block can sort after it.
+        while i > 0 && received[i - 1] > entry {
+            let previous = received[i - 1].clone();
+            received.set(i, previous);
+            i -= 1;
+        }
+        received.set(i, entry);
+    }
+
+    /// Marks thread `parent` read by the caller up to reply `message_id`.
+    fn read_reply(&mut self, parent: MessageId, message_id: MessageId) {
+        let account = env::predecessor_account_id();
+        self.assert_can_see(&message_id, &account);
+        let timestamp = self.message_timestamps[&message_id];
+        self.thread_markers
+            .entry(account.clone())
+            .or_insert_with(|| {
+                UnorderedMap::new(env::sha256(
+                    format!("thread-markers:{}", account).as_bytes(),
+                ))
+            })
+            .insert(
+                parent,
+                ThreadMarker {
+                    message_id,
+                    timestamp,
+                },
+            );
+    }
+
+    /// Marks the chat with `account` or channel `group` read by the caller up to `message_id`. A
+    /// thread reply marks its thread read instead.
     #[payable]
     pub fn read_message(
         &mut self,
@@ -441,111 +1881,841 @@ impl Curb {
         group: Option<Channel>,
         message_id: MessageId,
     ) {
-        if let Some(other) = account {
+        let group = group.map(Channel::normalized);
+        if let Some(parent) = self.reply_parents.get(&message_id).cloned() {
+            self.read_reply(parent, message_id);
+        } else if let Some(other) = account {
             let key = Curb::order_accounts(env::predecessor_account_id(), other.clone());
-            // TODO handle possibility that your message was put before last message currently seen.
-            self.chats
+            let chat = self
+                .chats
                 .get_mut(&key)
-                .unwrap()
-                .last_read
-                .insert(env::predecessor_account_id(), message_id);
-        } else if let Some(channel) = group {
+                .unwrap_or_else(|| CurbError::ChatDoesNotExist.panic());
+            let count = Curb::read_count(&chat.messages, &message_id);
             // TODO handle possibility that your message was put before last message currently seen.
-            self.channels
+            chat.last_read
+                .insert(env::predecessor_account_id(), message_id.clone());
+            self.read_markers.insert(
+                (
+                    env::predecessor_account_id(),
+                    MessageLocation::Chat(key.0, key.1),
+                ),
+                ReadMarker { message_id, count },
+            );
+        } else if let Some(channel) = group {
+            let info = self
+                .channels
                 .get_mut(&channel)
-                .unwrap()
-                .last_read
-                .insert(env::predecessor_account_id(), message_id);
+                .unwrap_or_else(|| CurbError::GroupDoesNotExist.panic());
+            let count = Curb::read_count(&info.messages, &message_id);
+            // TODO handle possibility that your message was put before last message currently seen.
+            info.last_read
+                .insert(env::predecessor_account_id(), message_id.clone());
+            self.read_markers.insert(
+                (
+                    env::predecessor_account_id(),
+                    MessageLocation::Channel(channel),
+                ),
+                ReadMarker { message_id, count },
+            );
         } else {
-            panic!("Either account or group need to be provided");
+            CurbError::MissingTarget.panic();
         }
     }
 
     #[payable]
     pub fn toggle_reaction(&mut self, message_id: MessageId, reaction: String) {
         // TODO handle storage payments
-        let reactions = self
-            .reactions
-            .entry(message_id.clone())
-            .or_insert(UnorderedMap::new(message_id.as_bytes()));
-        let tracker = &mut reactions
-            .entry(reaction.clone())
-            .or_insert(UnorderedSet::new(
-                format!("{} {}", message_id, reaction).as_bytes(),
-            ));
-        if tracker.contains(&env::predecessor_account_id()) {
-            tracker.remove(&env::predecessor_account_id());
+        let reaction = validation::reaction(
+            &reaction,
+            self.config.max_reaction_graphemes,
+            &self.config.allowed_reactions,
+        )
+        .unwrap_or_else(|e| e.panic());
+        let account = env::predecessor_account_id();
+        ensure(self.members.contains_key(&account), CurbError::NotAMember);
+        if let MessageLocation::Channel(channel) = self.assert_can_see(&message_id, &account) {
+            ensure(
+                !self.is_muted_in(channel, &account),
+                CurbError::MutedInGroup,
+            );
+        }
+        self.consume_rate_limit(RateLimited::Reaction);
+        let key = (message_id.clone(), reaction.clone());
+        let reacted = match self.reactors.get(&key) {
+            Some(reactors) => reactors.contains(&account),
+            None => false,
+        };
+        if reacted {
+            let reactors = self.reactors.get_mut(&key).unwrap();
+            reactors.remove(&account);
+            if reactors.is_empty() {
+                self.reactors.remove(&key).unwrap().clear();
+            }
+            if let Some(reactions) = self.account_reactions.get_mut(&account) {
+                reactions.remove(&key);
+            }
+            Curb::uncount_reaction(&mut self.reactions, &message_id, &reaction);
         } else {
-            tracker.insert(env::predecessor_account_id());
+            let counts = self.reactions.entry(message_id.clone()).or_default();
+            match counts.iter_mut().find(|c| c.reaction == reaction) {
+                Some(count) => count.count += 1,
+                None => {
+                    ensure(
+                        counts.len() < self.config.max_reactions_per_message as usize,
+                        CurbError::TooManyReactions,
+                    );
+                    counts.push(ReactionCount {
+                        reaction: reaction.clone(),
+                        count: 1,
+                    });
+                }
+            }
+            self.account_reactions
+                .entry(account.clone())
+                .or_insert_with(|| {
+                    UnorderedSet::new(env::sha256(
+                        format!("account-reactions:{}", account).as_bytes(),
+                    ))
+                })
+                .insert(key.clone());
+            self.reactors
+                .entry(key)
+                .or_insert_with(|| {
+                    UnorderedSet::new(env::sha256(
+                        format!("reactors:{}:{}", message_id, reaction).as_bytes(),
+                    ))
+                })
+                .insert(account);
         }
         self.register_activity();
     }
 
-    pub fn unread_messages(&self, account: AccountId) -> UnreadMessageInfo {
-        let mut unread_info = UnreadMessageInfo {
-            channels: HashMap::new(),
-            chats: HashMap::new(),
-            threads: HashMap::new(),
+    /// Expiry of a new message: `expires_at` or the conversation's message expiry from now,
+    /// whichever comes first.
+    fn expiry(
+        &self,
+        account: &Option<AccountId>,
+        group: &Option<Channel>,
+        expires_at: Option<u64>,
+    ) -> Option<u64> {
+        let now = env::block_timestamp_ms();
+        ensure(
+            expires_at.is_none_or(|expires_at| expires_at > now),
+            CurbError::InvalidExpiry,
+        );
+        let info = match (account, group) {
+            (Some(other), _) => self.chats.get(&Curb::order_accounts(
+                env::predecessor_account_id(),
+                other.clone(),
+            )),
+            (None, Some(channel)) => self.channels.get(channel),
+            (None, None) => None,
         };
-        for (channel, info) in self.channels.iter() {
-            let unread = info.messages.len() - self.find_last_seen_pos(&info, &account);
-            unread_info.channels.insert(
-                channel.name.clone(),
-                UnreadMessage {
-                    count: unread,
-                    last_seen: info.last_read.get(&account).cloned(),
-                },
-            );
+        let default = info
+            .and_then(|info| info.meta.message_expiry)
+            .map(|expiry| now.saturating_add(expiry));
+        match (expires_at, default) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
         }
+    }
 
-        for ((account1, account2), info) in self.chats.iter() {
-            if account1 != &account && account2 != &account {
-                continue;
-            }
-            let other_account = if account1 == &account {
-                account2
-            } else {
-                account1
-            };
-
-            let unread = info.messages.len() - self.find_last_seen_pos(&info, &account);
+    /// Makes new messages of a channel, set by its moderators, or of a chat, set by either party,
+    /// disappear after `expiry_ms`. `None` turns disappearing messages off.
+    #[payable]
+    pub fn set_message_expiry(
+        &mut self,
+        account: Option<AccountId>,
+        group: Option<Channel>,
+        expiry_ms: Option<u64>,
+    ) {
+        let group = group.map(Channel::normalized);
+        ensure(expiry_ms != Some(0), CurbError::InvalidExpiry);
+        let info = if let Some(other) = account {
+            let key = Curb::order_accounts(env::predecessor_account_id(), other);
+            self.chats
+                .get_mut(&key)
+                .unwrap_or_else(|| CurbError::ChatDoesNotExist.panic())
+        } else if let Some(channel) = group {
+            self.assert_group_moderator(&channel);
+            self.channels.get_mut(&channel).unwrap()
+        } else {
+            CurbError::MissingTarget.panic();
+        };
+        info.meta.message_expiry = expiry_ms;
+        self.register_activity();
+    }
 
-            unread_info.chats.insert(
-                other_account.clone(),
-                UnreadMessage {
-                    count: unread,
-                    last_seen: info.last_read.get(&account).cloned(),
-                },
+    /// Removes up to `limit` expired messages and thread replies of a chat or channel with their
+    /// threads, reactions and poll results, refunding their senders' storage deposits for the
+    /// removed bytes. Anyone may call it; returns the number of messages removed.
+    pub fn remove_expired_messages(
+        &mut self,
+        accounts: Option<(AccountId, AccountId)>,
+        group: Option<Channel>,
+        limit: Option<u32>,
+    ) -> u32 {
+        let group = group.map(Channel::normalized);
+        let location = if let Some((account1, account2)) = accounts {
+            let key = Curb::order_accounts(account1, account2);
+            ensure(self.chats.contains_key(&key), CurbError::ChatDoesNotExist);
+            MessageLocation::Chat(key.0, key.1)
+        } else if let Some(channel) = group {
+            ensure(
+                self.channels.contains_key(&channel),
+                CurbError::GroupDoesNotExist,
             );
-        }
+            MessageLocation::Channel(channel)
+        } else {
+            CurbError::MissingTarget.panic();
+        };
+        let now = env::block_timestamp_ms();
+        self.remove_messages(
+            location,
+            limit.unwrap_or(MESSAGES_REMOVED_PER_CALL),
+            &|_, message| message.is_expired(now),
+        )
+    }
 
-        unread_info
+    /// Sets the retention policy of `group`, applied by `prune`.
+    #[payable]
+    pub fn set_retention(&mut self, group: Channel, retention: RetentionPolicy) {
+        let group = group.normalized();
+        self.assert_group_moderator(&group);
+        ensure(
+            retention.max_messages != Some(0) && retention.max_age_ms != Some(0),
+            CurbError::InvalidRetentionPolicy,
+        );
+        self.channels.get_mut(&group).unwrap().meta.retention = retention;
+        self.register_activity();
     }
 
-    fn add_reactions_to_message(&self, message: Message) -> MessageWithReactions {
-        let mut message_with_reactions = MessageWithReactions {
-            id: message.id.clone(),
-            text: message.text,
-            timestamp: message.timestamp,
-            sender: message.sender,
-            reactions: None,
+    /// Removes up to `max_items` of the oldest messages of `group` that expired or fall outside
+    /// its retention policy, like `remove_expired_messages`. Anyone may call it, repeatedly until
+    /// it returns 0 to catch up on a large history.
+    pub fn prune(&mut self, group: Channel, max_items: Option<u32>) -> u32 {
+        let group = group.normalized();
+        let info = self
+            .channels
+            .get(&group)
+            .unwrap_or_else(|| CurbError::GroupDoesNotExist.panic());
+        let now = env::block_timestamp_ms();
+        let retention = &info.meta.retention;
+        let excess = match retention.max_messages {
+            Some(max) => info.messages.len().saturating_sub(max as usize),
+            None => 0,
         };
+        let cutoff = retention.max_age_ms.map(|age| now.saturating_sub(age));
+        self.remove_messages(
+            MessageLocation::Channel(group),
+            max_items.unwrap_or(MESSAGES_REMOVED_PER_CALL),
+            &|pos, message| {
+                pos.is_some_and(|pos| pos < excess)
+                    || message.is_expired(now)
+                    || cutoff.is_some_and(|cutoff| message.received_at < cutoff)
+            },
+        )
+    }
+
+    /// Removes up to `limit` messages of an existing chat or channel selected by `remove`, given
+    /// their position for top-level messages and `None` for thread replies, together with
+    /// everything stored about them, and refunds their senders. Removing a top-level message
+    /// removes its thread.
+    fn remove_messages(
+        &mut self,
+        location: MessageLocation,
+        limit: u32,
+        remove: &dyn Fn(Option<usize>, &Message) -> bool,
+    ) -> u32 {
+        let info = match &location {
+            MessageLocation::Chat(account1, account2) => self
+                .chats
+                .get_mut(&(account1.clone(), account2.clone()))
+                .unwrap(),
+            MessageLocation::Channel(channel) => self.channels.get_mut(channel).unwrap(),
+        };
+        let limit = limit as usize;
+        let mut positions = vec![];
+        let mut removed = vec![];
+        let mut kept = vec![];
+        for (pos, message) in std::mem::take(&mut info.messages).into_iter().enumerate() {
+            if removed.len() < limit && remove(Some(pos), &message) {
+                positions.push(pos as u64);
+                removed.push(message);
+            } else {
+                kept.push(message);
+            }
+        }
+        info.messages = kept;
+        if !removed.is_empty() {
+            // Read markers count the messages up to the one read, so they shift with the removals.
+            let readers: Vec<AccountId> = info.last_read.keys().cloned().collect();
+            for reader in readers {
+                let key = (reader.clone(), location.clone());
+                let marker = match self.read_markers.get_mut(&key) {
+                    Some(marker) => marker,
+                    None => continue,
+                };
+                marker.count -= positions.iter().filter(|p| **p < marker.count).count() as u64;
+                if removed.iter().any(|m| m.id == marker.message_id) {
+                    match marker.count {
+                        0 => {
+                            self.read_markers.remove(&key);
+                            info.last_read.remove(&reader);
+                        }
+                        count => {
+                            marker.message_id = info.messages[count as usize - 1].id.clone();
+                            info.last_read.insert(reader, marker.message_id.clone());
+                        }
+                    }
+                }
+            }
+            if let Some(count) = self.message_counts.get_mut(&location) {
+                *count -= removed.len() as u64;
+            }
+            if let MessageLocation::Channel(channel) = &location {
+                self.unindex_tags(channel, &removed);
+            }
+        }
+
+        let replies = self.remove_replies(&location, &removed, limit - removed.len(), remove);
+        let count = removed.len() + replies.len();
+        for message in std::mem::take(&mut removed) {
+            removed.extend(self.threads.remove(&message.id).unwrap_or_default());
+            removed.push(message);
+        }
+        removed.extend(replies);
+        self.release_storage(&removed);
+        count as u32
+    }
+
+    /// Removes up to `limit` thread replies of `location` selected by `remove`, skipping the
+    /// threads of the `removed` top-level messages, and returns them.
+    fn remove_replies(
+        &mut self,
+        location: &MessageLocation,
+        removed: &[Message],
+        limit: usize,
+        remove: &dyn Fn(Option<usize>, &Message) -> bool,
+    ) -> Vec<Message> {
+        let mut parents = match self.thread_parents.remove(location) {
+            Some(parents) => parents,
+            None => return vec![],
+        };
+        parents.retain(|parent| !removed.iter().any(|m| &m.id == parent));
+        let mut replies = vec![];
+        for parent in parents.iter() {
+            if replies.len() >= limit {
+                break;
+            }
+            let thread = self.threads.get_mut(parent).unwrap();
+            let mut kept = vec![];
+            for reply in std::mem::take(thread) {
+                if replies.len() < limit && remove(None, &reply) {
+                    replies.push(reply);
+                } else {
+                    kept.push(reply);
+                }
+            }
+            *thread = kept;
+        }
+        parents.retain(|parent| {
+            let empty = self.threads.get(parent).is_none_or(Vec::is_empty);
+            if empty {
+                self.threads.remove(parent);
+            }
+            !empty
+        });
+        if !parents.is_empty() {
+            self.thread_parents.insert(location.clone(), parents);
+        }
+        replies
+    }
+
+    /// Removes what is stored about `messages` and refunds their senders' storage deposits for
+    /// the released bytes.
+    fn release_storage(&mut self, messages: &[Message]) {
+        let mut released: HashMap<AccountId, u64> = HashMap::new();
+        for message in messages {
+            self.remove_message_data(&message.id);
+            *released.entry(message.sender.clone()).or_default() +=
+                borsh::to_vec(message).unwrap().len() as u64;
+        }
+        for (sender, bytes) in released {
+            if let Some(deposit) = self.storage_deposits.get_mut(&sender) {
+                let refund = std::cmp::min(*deposit, bytes as Balance * env::storage_byte_cost());
+                *deposit -= refund;
+                if refund > 0 {
+                    Promise::new(sender).transfer(refund);
+                }
+            }
+        }
+    }
+
+    /// Adds top-level message `message_id` of `channel` to the index of each of its `tags`,
+    /// dropping the oldest ids of a tag beyond `max_messages_per_tag`. New tags are skipped once
+    /// the channel has `max_tags_per_channel` tags.
+    fn index_tags(&mut self, channel: &Channel, message_id: &MessageId, tags: Vec<String>) {
+        if tags.is_empty() {
+            return;
+        }
+        let max = self.config.max_messages_per_tag as usize;
+        let max_tags = self.config.max_tags_per_channel;
+        let index = self.channel_tags.entry(channel.clone()).or_insert_with(|| {
+            UnorderedMap::new(env::sha256(format!("tags:{}", channel.name).as_bytes()))
+        });
+        for tag in tags {
+            if !index.contains_key(&tag) && index.len() >= max_tags {
+                continue;
+            }
+            let ids = index.entry(tag).or_default();
+            // Cursors only grow, also across a tag being emptied and indexed again.
+            let cursor = match ids.last() {
+                Some((last, _)) => std::cmp::max(last + 1, env::block_timestamp()),
+                None => env::block_timestamp(),
+            };
+            ids.push((cursor, message_id.clone()));
+            if ids.len() > max {
+                ids.drain(..ids.len() - max);
+            }
+        }
+    }
+
+    /// Removes top-level `messages` of `channel` from the index, looking up only the tags their
+    /// texts contain.
+    fn unindex_tags(&mut self, channel: &Channel, messages: &[Message]) {
+        let index = match self.channel_tags.get_mut(channel) {
+            Some(index) => index,
+            None => return,
+        };
+        for message in messages {
+            if matches!(message.format, MessageFormat::Code { .. }) {
+                continue;
+            }
+            for tag in validation::tags(&message.text, MAX_TAGS_PER_MESSAGE) {
+                let emptied = match index.get_mut(&tag) {
+                    Some(ids) => {
+                        ids.retain(|(_, id)| id != &message.id);
+                        ids.is_empty()
+                    }
+                    None => false,
+                };
+                if emptied {
+                    index.remove(&tag);
+                }
+            }
+        }
+    }
+
+    /// Removes what is stored about a message apart from the message itself.
+    fn remove_message_data(&mut self, message_id: &MessageId) {
+        self.message_locations.remove(message_id);
+        self.message_timestamps.remove(message_id);
+        self.reply_parents.remove(message_id);
+        if let Some(counts) = self.reactions.remove(message_id) {
+            for count in counts {
+                let key = (message_id.clone(), count.reaction);
+                if let Some(mut reactors) = self.reactors.remove(&key) {
+                    for reactor in reactors.iter() {
+                        if let Some(reactions) = self.account_reactions.get_mut(reactor) {
+                            reactions.remove(&key);
+                        }
+                    }
+                    reactors.clear();
+                }
+            }
+        }
+        self.remove_poll(message_id);
+    }
+
+    /// Drops the tally of poll `message_id` along with the voters' index entries.
+    fn remove_poll(&mut self, message_id: &MessageId) {
+        if let Some(mut tally) = self.poll_tallies.remove(message_id) {
+            for voter in tally.ballots.keys() {
+                if let Some(polls) = self.account_ballots.get_mut(voter) {
+                    polls.remove(message_id);
+                }
+            }
+            tally.ballots.clear();
+        }
+    }
+
+    /// Records the options the caller votes for in poll `message_id`, replacing any earlier vote
+    /// until the poll's deadline. An empty `options` withdraws the vote.
+    #[payable]
+    pub fn vote(&mut self, message_id: MessageId, options: Vec<u8>) {
+        // TODO handle storage payments
+        let account = env::predecessor_account_id();
+        ensure(self.members.contains_key(&account), CurbError::NotAMember);
+        if let MessageLocation::Channel(channel) = self.assert_can_see(&message_id, &account) {
+            ensure(
+                !self.is_muted_in(channel, &account),
+                CurbError::MutedInGroup,
+            );
+        }
+        let poll = self
+            .find_message(&message_id)
+            .and_then(|m| m.poll.as_ref())
+            .unwrap_or_else(|| CurbError::NotAPoll.panic());
+        ensure(
+            poll.deadline
+                .is_none_or(|deadline| env::block_timestamp_ms() <= deadline),
+            CurbError::PollClosed,
+        );
+        let options = validation::poll_vote(options, poll).unwrap_or_else(|e| e.panic());
+        let option_count = poll.options.len();
+        self.consume_rate_limit(RateLimited::Reaction);
+
+        let polls = self
+            .account_ballots
+            .entry(account.clone())
+            .or_insert_with(|| {
+                UnorderedSet::new(env::sha256(
+                    format!("account-ballots:{}", account).as_bytes(),
+                ))
+            });
+        if options.is_empty() {
+            polls.remove(&message_id);
+        } else {
+            polls.insert(message_id.clone());
+        }
+        let tally = self
+            .poll_tallies
+            .entry(message_id.clone())
+            .or_insert_with(|| PollTally {
+                votes: vec![0; option_count],
+                ballots: UnorderedMap::new(env::sha256(
+                    format!("ballots:{}", message_id).as_bytes(),
+                )),
+            });
+        if let Some(previous) = tally.ballots.remove(&account) {
+            for option in previous {
+                tally.votes[option as usize] -= 1;
+            }
+        }
+        if !options.is_empty() {
+            for option in options.iter() {
+                tally.votes[*option as usize] += 1;
+            }
+            tally.ballots.insert(account, options);
+        }
+        self.register_activity();
+    }
+
+    /// Panics unless `message_id` exists and `account` is a member of the channel or a party to
+    /// the chat it was sent in. Outsiders are told chat messages do not exist, so message ids do
+    /// not reveal anything about private conversations.
+    fn assert_can_see(&self, message_id: &MessageId, account: &AccountId) -> &MessageLocation {
+        let location = self
+            .message_locations
+            .get(message_id)
+            .unwrap_or_else(|| CurbError::MessageDoesNotExist.panic());
+        match location {
+            MessageLocation::Channel(channel) => {
+                let members = self
+                    .channel_members
+                    .get(channel)
+                    .unwrap_or_else(|| CurbError::MessageDoesNotExist.panic());
+                ensure(members.contains(account), CurbError::NotAGroupMember);
+            }
+            MessageLocation::Chat(account1, account2) => ensure(
+                account1 == account || account2 == account,
+                CurbError::MessageDoesNotExist,
+            ),
+        }
+        location
+    }
+
+    /// Panics unless `message` is posted in the thread of and replies to top-level messages of
+    /// `location`, the conversation it is sent to, and forwards a top-level message its sender
+    /// can see.
+    fn assert_references(
+        &self,
+        message: &Message,
+        parent_message: &Option<MessageId>,
+        location: &MessageLocation,
+    ) {
+        if let Some(parent) = parent_message {
+            ensure(
+                self.message_locations.get(parent) == Some(location)
+                    && self.find_message(parent).is_some(),
+                CurbError::InvalidParentMessage,
+            );
+        }
+        if let Some(reply_to) = &message.reply_to {
+            ensure(
+                self.message_locations.get(reply_to) == Some(location)
+                    && self.find_message(reply_to).is_some(),
+                CurbError::InvalidReplyTarget,
+            );
+        }
+        if let Some(source) = &message.forwarded_from {
+            self.assert_can_see(source, &message.sender);
+            ensure(
+                self.find_message(source).is_some(),
+                CurbError::MessageDoesNotExist,
+            );
+        }
+    }
+
+    /// Top-level message `message_id` of a channel or chat, `None` for thread replies and expired
+    /// messages.
+    fn find_message(&self, message_id: &MessageId) -> Option<&Message> {
+        let info = match self.message_locations.get(message_id)? {
+            MessageLocation::Channel(channel) => self.channels.get(channel)?,
+            MessageLocation::Chat(account1, account2) => {
+                self.chats.get(&(account1.clone(), account2.clone()))?
+            }
+        };
+        // Messages are ordered by timestamp first, so only those sent at the same time are scanned.
+        let timestamp = *self.message_timestamps.get(message_id)?;
+        let start = info.messages.partition_point(|m| m.timestamp < timestamp);
+        info.messages[start..]
+            .iter()
+            .take_while(|m| m.timestamp == timestamp)
+            .find(|m| &m.id == message_id)
+            .filter(|m| !m.is_expired(env::block_timestamp_ms()))
+    }
+
+    /// Channel message `message_id`, top-level or a thread reply, with the thread it replies in.
+    fn find_received(&self, message_id: &MessageId) -> Option<ReceivedMessage> {
+        let parent = match self.reply_parents.get(message_id) {
+            Some(parent) => parent,
+            None => {
+                return self
+                    .find_message(message_id)
+                    .map(|message| ReceivedMessage {
+                        parent: None,
+                        message: message.clone(),
+                    })
+            }
+        };
+        let timestamp = *self.message_timestamps.get(message_id)?;
+        let thread = self.threads.get(parent)?;
+        let start = thread.partition_point(|m| m.timestamp < timestamp);
+        thread[start..]
+            .iter()
+            .take_while(|m| m.timestamp == timestamp)
+            .find(|m| &m.id == message_id)
+            .filter(|m| !m.is_expired(env::block_timestamp_ms()))
+            .map(|message| ReceivedMessage {
+                parent: Some(parent.clone()),
+                message: message.clone(),
+            })
+    }
+
+    fn preview(&self, message_id: &Option<MessageId>) -> Option<MessagePreview> {
+        let message = self.find_message(message_id.as_ref()?)?;
+        Some(MessagePreview {
+            id: message.id.clone(),
+            sender: message.sender.clone(),
+            timestamp: message.timestamp,
+            text: message.text.chars().take(PREVIEW_LENGTH).collect(),
+            deleted: message.deleted,
+        })
+    }
+
+    fn uncount_reaction(
+        reactions: &mut LookupMap<MessageId, Vec<ReactionCount>>,
+        message_id: &MessageId,
+        reaction: &String,
+    ) {
+        if let Some(counts) = reactions.get_mut(message_id) {
+            if let Some(pos) = counts.iter().position(|c| &c.reaction == reaction) {
+                counts[pos].count -= 1;
+                if counts[pos].count == 0 {
+                    counts.remove(pos);
+                }
+            }
+            if counts.is_empty() {
+                reactions.remove(message_id);
+            }
+        }
+    }
+
+    /// Results of poll `message_id`, with `myVotes` set for `viewer`.
+    pub fn get_poll(
+        &self,
+        message_id: MessageId,
+        viewer: Option<AccountId>,
+    ) -> Option<PollResults> {
+        self.poll_results(self.find_message(&message_id)?, &viewer)
+    }
+
+    /// Accounts that reacted to `message_id` with `reaction`.
+    pub fn get_reactors(
+        &self,
+        message_id: MessageId,
+        reaction: String,
+        offset: Option<usize>,
+        length: Option<usize>,
+    ) -> Vec<&AccountId> {
+        let reaction = validation::normalize(&reaction);
+        match self.reactors.get(&(message_id, reaction)) {
+            Some(reactors) => reactors
+                .iter()
+                .skip(offset.unwrap_or_default())
+                .take(length.unwrap_or(usize::MAX))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Unread counts of the channels `account` is a member of, of its chats and of the threads it
+    /// follows, computed from per-conversation counters so the cost only depends on the
+    /// account's own conversations.
+    pub fn unread_messages(&self, account: AccountId) -> UnreadMessageInfo {
+        let mut unread_info = UnreadMessageInfo {
+            channels: HashMap::new(),
+            chats: HashMap::new(),
+            threads: HashMap::new(),
+        };
+        if let Some(channels) = self.member_channels.get(&account) {
+            for channel in channels.iter() {
+                let location = MessageLocation::Channel(channel.clone());
+                unread_info
+                    .channels
+                    .insert(channel.name.clone(), self.unread(&account, location));
+            }
+        }
 
-        let mut reactions: HashMap<String, Vec<AccountId>> = HashMap::new();
+        if let Some(partners) = self.member_chats.get(&account) {
+            for other_account in partners.iter() {
+                let (account1, account2) =
+                    Curb::order_accounts(account.clone(), other_account.clone());
+                let location = MessageLocation::Chat(account1, account2);
+                unread_info
+                    .chats
+                    .insert(other_account.clone(), self.unread(&account, location));
+            }
+        }
 
-        if let Some(r) = self.reactions.get(&message.id) {
-            for (k, v) in r.iter() {
-                reactions.insert(k.clone(), v.iter().map(|a| a.clone()).collect());
+        if let Some(markers) = self.thread_markers.get(&account) {
+            for (parent, marker) in markers.iter() {
+                let visible = match self.message_locations.get(parent) {
+                    Some(MessageLocation::Channel(channel)) => self
+                        .channel_members
+                        .get(channel)
+                        .is_some_and(|members| members.contains(&account)),
+                    Some(MessageLocation::Chat(_, _)) => true,
+                    None => false,
+                };
+                let thread = match self.threads.get(parent) {
+                    Some(thread) if visible => thread,
+                    _ => continue,
+                };
+                let read = thread.partition_point(|m| m.timestamp <= marker.timestamp);
+                unread_info.threads.insert(
+                    parent.clone(),
+                    UnreadMessage {
+                        count: thread.len() - read,
+                        last_seen: Some(marker.message_id.clone()),
+                    },
+                );
             }
-            message_with_reactions.reactions = Some(reactions);
         }
 
-        message_with_reactions
+        unread_info
+    }
+
+    fn unread(&self, account: &AccountId, location: MessageLocation) -> UnreadMessage {
+        let total = self.message_counts.get(&location).copied().unwrap_or(0);
+        match self.read_markers.get(&(account.clone(), location)) {
+            Some(marker) => UnreadMessage {
+                count: total.saturating_sub(marker.count) as usize,
+                last_seen: Some(marker.message_id.clone()),
+            },
+            None => UnreadMessage {
+                count: total as usize,
+                last_seen: None,
+            },
+        }
+    }
+
+    fn count_message(&mut self, location: MessageLocation) {
+        *self.message_counts.entry(location).or_insert(0) += 1;
+    }
+
+    fn add_chat_partners(&mut self, account1: &AccountId, account2: &AccountId) {
+        for (account, partner) in [(account1, account2), (account2, account1)] {
+            self.member_chats
+                .entry(account.clone())
+                .or_insert_with(|| {
+                    UnorderedSet::new(env::sha256(format!("chats:{}", account).as_bytes()))
+                })
+                .insert(partner.clone());
+        }
+    }
+
+    fn poll_results(&self, message: &Message, viewer: &Option<AccountId>) -> Option<PollResults> {
+        let poll = message.poll.clone()?;
+        let tally = self.poll_tallies.get(&message.id);
+        let (votes, voters) = match tally {
+            Some(tally) => (tally.votes.clone(), tally.ballots.len()),
+            None => (vec![0; poll.options.len()], 0),
+        };
+        let my_votes = match (tally, viewer) {
+            (Some(tally), Some(viewer)) => tally.ballots.get(viewer).cloned().unwrap_or_default(),
+            _ => vec![],
+        };
+        Some(PollResults {
+            closed: poll
+                .deadline
+                .is_some_and(|deadline| env::block_timestamp_ms() > deadline),
+            question: poll.question,
+            options: poll.options,
+            multiple_choice: poll.multiple_choice,
+            deadline: poll.deadline,
+            votes,
+            voters,
+            my_votes,
+        })
+    }
+
+    fn add_reactions_to_message(
+        &self,
+        message: Message,
+        viewer: &Option<AccountId>,
+    ) -> MessageWithReactions {
+        let reactions = match self.reactions.get(&message.id) {
+            Some(counts) => counts
+                .iter()
+                .map(|c| ReactionSummary {
+                    reaction: c.reaction.clone(),
+                    count: c.count,
+                    reacted_by_me: match viewer {
+                        Some(viewer) => self
+                            .reactors
+                            .get(&(message.id.clone(), c.reaction.clone()))
+                            .is_some_and(|reactors| reactors.contains(viewer)),
+                        None => false,
+                    },
+                })
+                .collect(),
+            None => vec![],
+        };
+        MessageWithReactions {
+            poll: self.poll_results(&message, viewer),
+            reply_to: self.preview(&message.reply_to),
+            forwarded_from: self.preview(&message.forwarded_from),
+            id: message.id,
+            text: message.text,
+            timestamp: message.timestamp,
+            sender: message.sender,
+            deleted: message.deleted,
+            expires_at: message.expires_at,
+            received_at: message.received_at,
+            attachments: message.attachments,
+            format: message.format,
+            link_previews: message.link_previews,
+            reactions,
+        }
     }
 
     fn add_thread_to_message(
         &self,
         message: MessageWithReactions,
+        viewer: &Option<AccountId>,
     ) -> MessageWithReactionsAndThread {
         let empty_thread: Vec<Message> = vec![];
         MessageWithReactionsAndThread {
@@ -553,90 +2723,179 @@ impl Curb {
             text: message.text,
             timestamp: message.timestamp,
             sender: message.sender,
+            deleted: message.deleted,
+            expires_at: message.expires_at,
+            received_at: message.received_at,
+            attachments: message.attachments,
+            format: message.format,
+            link_previews: message.link_previews,
+            poll: message.poll,
             reactions: message.reactions,
+            reply_to: message.reply_to,
+            forwarded_from: message.forwarded_from,
             thread: self
                 .threads
                 .get(&message.id)
                 .unwrap_or(&empty_thread)
-                .into_iter()
-                .map(|m| self.add_reactions_to_message(m.clone()))
+                .iter()
+                .filter(|m| !m.is_expired(env::block_timestamp_ms()))
+                .map(|m| self.add_reactions_to_message(m.clone(), viewer))
                 .collect(),
         }
     }
 
+    /// Messages of a chat or channel with their threads, `offset` and `length` selecting a range
+    /// of the top-level messages. Reactions are aggregated per reaction, with `reactedByMe` set
+    /// for `viewer`; `get_reactors` lists who reacted.
     pub fn get_messages(
         &self,
         accounts: Option<(AccountId, AccountId)>,
         group: Option<Channel>,
         offset: Option<usize>,
         length: Option<usize>,
+        viewer: Option<AccountId>,
     ) -> Vec<MessageWithReactionsAndThread> {
-        let start = if let Some(pos) = offset { pos } else { 0 };
-        if let Some((account1, account2)) = accounts {
-            let key = Curb::order_accounts(account1, account2);
+        let group = group.map(Channel::normalized);
+        let info = if let Some((account1, account2)) = accounts {
+            self.chats.get(&Curb::order_accounts(account1, account2))
+        } else if let Some(channel) = group {
+            self.channels.get(&channel)
+        } else {
+            CurbError::MissingTarget.panic();
+        };
+        let messages = match info {
+            Some(info) => &info.messages,
+            None => return vec![],
+        };
+        messages
+            .iter()
+            .filter(|m| !m.is_expired(env::block_timestamp_ms()))
+            .skip(offset.unwrap_or_default())
+            .take(length.unwrap_or(usize::MAX))
+            .map(|m| self.add_reactions_to_message(m.clone(), &viewer))
+            .map(|m| self.add_thread_to_message(m, &viewer))
+            .collect()
+    }
 
-            match self.chats.get(&key) {
-                Some(e) => {
-                    let end = if let Some(len) = length {
-                        std::cmp::min(len + start, e.messages.len())
-                    } else {
-                        e.messages.len() - start
-                    };
-
-                    e.messages[start..end]
-                        .into_iter()
-                        .map(|m| self.add_reactions_to_message(m.clone()))
-                        .map(|m| self.add_thread_to_message(m))
-                        .collect()
-                }
-                None => vec![],
+    /// Indexed messages of `group` tagged with `tag`, with or without its `#`, newest first.
+    /// `before` is the `next` cursor of the previous page. Empty unless `tagIndex` is enabled.
+    pub fn get_messages_by_tag(
+        &self,
+        group: Channel,
+        tag: String,
+        before: Option<u64>,
+        length: Option<usize>,
+        viewer: Option<AccountId>,
+    ) -> TaggedMessages {
+        let group = group.normalized();
+        let mut page = TaggedMessages {
+            messages: vec![],
+            next: None,
+        };
+        let ids = match self
+            .channel_tags
+            .get(&group)
+            .and_then(|tags| tags.get(&validation::tag(&tag)))
+        {
+            Some(ids) => ids,
+            None => return page,
+        };
+        let length = length.unwrap_or(usize::MAX);
+        let mut last = None;
+        let older = ids
+            .iter()
+            .rev()
+            .filter(|(cursor, _)| before.is_none_or(|before| *cursor < before));
+        for (cursor, id) in older {
+            let message = match self.find_message(id).filter(|m| !m.deleted) {
+                Some(message) => message,
+                None => continue,
+            };
+            if page.messages.len() == length {
+                page.next = last;
+                break;
             }
-        } else if let Some(channel) = group {
-            match self.channels.get(&channel) {
-                Some(e) => {
-                    let end = if let Some(len) = length {
-                        std::cmp::min(len + start, e.messages.len())
-                    } else {
-                        e.messages.len() - start
-                    };
-                    e.messages[start..end]
-                        .into_iter()
-                        .map(|m| self.add_reactions_to_message(m.clone()))
-                        .map(|m| self.add_thread_to_message(m))
-                        .collect()
+            page.messages
+                .push(self.add_reactions_to_message(message.clone(), &viewer));
+            last = Some(*cursor);
+        }
+        page
+    }
+
+    /// Messages of `group`, top-level and thread replies, in the order they were received. `after`
+    /// is the `(receivedAt, id)` of the last message of the previous page, so indexers can follow
+    /// a channel without reading its history again. Expired and removed messages are skipped.
+    pub fn get_messages_received_after(
+        &self,
+        group: Channel,
+        after: Option<(u64, MessageId)>,
+        length: Option<usize>,
+    ) -> Vec<ReceivedMessage> {
+        let received = match self.received_order.get(&group.normalized()) {
+            Some(received) => received,
+            None => return vec![],
+        };
+        // Binary search for the first entry past the cursor.
+        let (mut start, mut end) = (0, received.len());
+        if let Some(after) = &after {
+            while start < end {
+                let mid = start + (end - start) / 2;
+                if &received[mid] <= after {
+                    start = mid + 1;
+                } else {
+                    end = mid;
                 }
-                None => vec![],
             }
-        } else {
-            panic!("Either account or group need to be provided");
         }
+        (start..received.len())
+            .filter_map(|i| self.find_received(&received[i].1))
+            .take(length.unwrap_or(usize::MAX))
+            .collect()
     }
 
     pub fn get_members(&self, group: Option<Channel>) -> Vec<UserInfo> {
+        let group = group.map(Channel::normalized);
         if let Some(group) = group {
             match self.channel_members.get(&group) {
                 Some(cm) => cm
                     .iter()
-                    .map(|m| UserInfo {
-                        id: m.clone(),
-                        active: self.is_active(*self.members.get(&m).unwrap()),
-                    })
+                    .map(|m| self.user_info(m, *self.members.get(m).unwrap()))
                     .collect(),
                 None => vec![],
             }
         } else {
             self.members
                 .iter()
-                .map(|(m, timestamp)| UserInfo {
-                    id: m.clone(),
-                    active: self.is_active(*timestamp),
-                })
+                .map(|(m, timestamp)| self.user_info(m, *timestamp))
                 .collect()
         }
     }
 
-    fn is_active(&self, timestamp: u64) -> bool {
-        env::block_timestamp_ms() - timestamp < ACTIVE_MS_THRESHOLD
+    /// Presence of `account`, last seen at `last_seen`. An unexpired status sets it while the
+    /// account was seen within `awayMsThreshold`, except that online members become away after
+    /// `activeMsThreshold`.
+    fn user_info(&self, account: &AccountId, last_seen: u64) -> UserInfo {
+        let now = env::block_timestamp_ms();
+        let idle = now.saturating_sub(last_seen);
+        let status = self
+            .statuses
+            .get(account)
+            .filter(|status| status.expires_at.is_none_or(|expires_at| now < expires_at));
+        let presence = match status.map(|status| status.presence) {
+            _ if idle >= self.config.away_ms_threshold => Presence::Offline,
+            Some(Presence::Online) | None if idle >= self.config.active_ms_threshold => {
+                Presence::Away
+            }
+            Some(presence) => presence,
+            None => Presence::Online,
+        };
+        UserInfo {
+            id: account.clone(),
+            active: presence != Presence::Offline && idle < self.config.active_ms_threshold,
+            presence,
+            status_text: status.and_then(|status| status.text.clone()),
+            last_seen,
+        }
     }
 
     pub fn get_groups(&self, account: Option<AccountId>) -> Vec<&Channel> {
@@ -650,6 +2909,45 @@ impl Curb {
         }
     }
 
+    pub fn get_moderation_log(
+        &self,
+        group: Option<Channel>,
+        offset: Option<usize>,
+        length: Option<usize>,
+    ) -> Vec<&ModerationEntry> {
+        let group = group.map(Channel::normalized);
+        self.moderation_log
+            .iter()
+            .filter(|e| group.is_none() || e.group == group)
+            .skip(offset.unwrap_or_default())
+            .take(length.unwrap_or(usize::MAX))
+            .collect()
+    }
+
+    pub fn get_banned(&self, group: Option<Channel>) -> Vec<&AccountId> {
+        let group = group.map(Channel::normalized);
+        if let Some(group) = group {
+            match self.channel_bans.get(&group) {
+                Some(bans) => bans.iter().collect(),
+                None => vec![],
+            }
+        } else {
+            self.banned.iter().collect()
+        }
+    }
+
+    pub fn get_muted(&self, group: Channel) -> HashMap<AccountId, u64> {
+        let group = group.normalized();
+        match self.channel_mutes.get(&group) {
+            Some(mutes) => mutes
+                .iter()
+                .filter(|(_, until)| **until > env::block_timestamp_ms())
+                .map(|(account, until)| (account.clone(), *until))
+                .collect(),
+            None => HashMap::new(),
+        }
+    }
+
     pub fn get_keys(&self, account: AccountId) -> Vec<PublicKey> {
         match self.member_keys.get(&account) {
             Some(key) => vec![key.clone()],
@@ -658,9 +2956,186 @@ impl Curb {
     }
 
     pub fn channel_info(&self, group: Channel) -> Option<&ChannelMetadata> {
+        let group = group.normalized();
         self.channels.get(&group).map(|c| &c.meta)
     }
 
+    fn assert_owner(&self) {
+        ensure(
+            env::predecessor_account_id() == self.owner,
+            CurbError::NotTheOwner,
+        );
+    }
+
+    /// Starts an ownership transfer which `new_owner` completes with `accept_ownership`.
+    pub fn transfer_ownership(&mut self, new_owner: AccountId) {
+        self.assert_owner();
+        self.pending_owner = Some(new_owner);
+    }
+
+    pub fn accept_ownership(&mut self) {
+        ensure(
+            self.pending_owner.as_ref() == Some(&env::predecessor_account_id()),
+            CurbError::NotThePendingOwner,
+        );
+        self.owner = self.pending_owner.take().unwrap();
+    }
+
+    pub fn set_name(&mut self, name: String) {
+        self.assert_owner();
+        ensure(!name.is_empty(), CurbError::InvalidName);
+        self.name = name;
+    }
+
+    pub fn set_config(&mut self, config: Config) {
+        self.assert_owner();
+        let mut config = config;
+        ensure(config.max_channel_name_length > 0, CurbError::InvalidConfig);
+        ensure(config.max_reaction_graphemes > 0, CurbError::InvalidConfig);
+        ensure(
+            config.max_reactions_per_message > 0,
+            CurbError::InvalidConfig,
+        );
+        config.default_channel.name =
+            validation::channel_name(&config.default_channel.name, config.max_channel_name_length)
+                .unwrap_or_else(|e| e.panic());
+        if let Some(allowed) = config.allowed_reactions.as_mut() {
+            for reaction in allowed.iter_mut() {
+                *reaction = validation::reaction(reaction, config.max_reaction_graphemes, &None)
+                    .unwrap_or_else(|e| e.panic());
+            }
+        }
+        ensure(
+            self.members.is_empty() || self.channels.contains_key(&config.default_channel),
+            CurbError::GroupDoesNotExist,
+        );
+        ensure(config.active_ms_threshold > 0, CurbError::InvalidConfig);
+        ensure(
+            config.away_ms_threshold >= config.active_ms_threshold,
+            CurbError::InvalidConfig,
+        );
+        ensure(config.max_message_length > 0, CurbError::InvalidConfig);
+        ensure(
+            config.max_attachments_per_message > 0,
+            CurbError::InvalidConfig,
+        );
+        ensure(
+            config.max_link_previews_per_message > 0,
+            CurbError::InvalidConfig,
+        );
+        ensure(
+            (2..=256).contains(&config.max_poll_options),
+            CurbError::InvalidConfig,
+        );
+        ensure(config.max_messages_per_tag > 0, CurbError::InvalidConfig);
+        ensure(config.max_tags_per_channel > 0, CurbError::InvalidConfig);
+        self.config = config;
+    }
+
+    pub fn add_to_allowlist(&mut self, accounts: Vec<AccountId>) {
+        self.assert_owner();
+        for account in accounts {
+            self.allowlist.insert(account);
+        }
+    }
+
+    pub fn remove_from_allowlist(&mut self, accounts: Vec<AccountId>) {
+        self.assert_owner();
+        for account in accounts.iter() {
+            self.allowlist.remove(account);
+        }
+    }
+
+    pub fn get_allowlist(&self) -> Vec<&AccountId> {
+        self.allowlist.iter().collect()
+    }
+
+    /// Registers an invite by the hex encoded sha256 hash of `"{account}:{code}"`. Only `account`
+    /// can redeem it with `join_with_invite`, so a code seen in a pending transaction cannot be
+    /// used by anyone else.
+    pub fn create_invite(&mut self, code_hash: String) {
+        let account = env::predecessor_account_id();
+        ensure(
+            account == self.owner || self.members.contains_key(&account),
+            CurbError::NotAMember,
+        );
+        ensure(
+            code_hash.len() == 64 && code_hash.bytes().all(|b| b.is_ascii_hexdigit()),
+            CurbError::InvalidInviteCodeHash,
+        );
+        let code_hash = code_hash.to_ascii_lowercase();
+        ensure(
+            !self.invites.contains_key(&code_hash),
+            CurbError::InviteAlreadyExists,
+        );
+        if account != self.owner {
+            let created = self.invites_created.get(&account).copied().unwrap_or(0);
+            ensure(
+                created < self.config.invite_quota,
+                CurbError::InviteQuotaExceeded,
+            );
+            self.invites_created.insert(account.clone(), created + 1);
+        }
+        self.invites.insert(
+            code_hash,
+            InviteInfo {
+                created_by: account,
+                created_at: env::block_timestamp_ms(),
+            },
+        );
+    }
+
+    pub fn revoke_invite(&mut self, code_hash: String) {
+        let code_hash = code_hash.to_ascii_lowercase();
+        let created_by = match self.invites.get(&code_hash) {
+            Some(invite) => invite.created_by.clone(),
+            None => CurbError::InvalidInviteCode.panic(),
+        };
+        let account = env::predecessor_account_id();
+        ensure(
+            account == created_by || account == self.owner,
+            CurbError::NotInviteCreator,
+        );
+        self.invites.remove(&code_hash);
+        if let Some(created) = self.invites_created.get_mut(&created_by) {
+            *created = created.saturating_sub(1);
+        }
+    }
+
+    pub fn get_invites(&self, created_by: Option<AccountId>) -> Vec<&String> {
+        self.invites
+            .iter()
+            .filter(|(_, invite)| {
+                created_by.is_none() || created_by.as_ref() == Some(&invite.created_by)
+            })
+            .map(|(code_hash, _)| code_hash)
+            .collect()
+    }
+
+    pub fn remaining_invites(&self, account: AccountId) -> u32 {
+        if account == self.owner {
+            return u32::MAX;
+        }
+        let created = self.invites_created.get(&account).copied().unwrap_or(0);
+        self.config.invite_quota.saturating_sub(created)
+    }
+
+    pub fn get_error_codes(&self) -> Vec<ErrorInfo> {
+        CurbError::ALL.iter().map(|e| e.info()).collect()
+    }
+
+    pub fn get_config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn get_owner(&self) -> &AccountId {
+        &self.owner
+    }
+
+    pub fn get_pending_owner(&self) -> Option<&AccountId> {
+        self.pending_owner.as_ref()
+    }
+
     pub fn created_at(&self) -> u64 {
         self.created_at
     }
@@ -669,3 +3144,1133 @@ impl Curb {
         &self.name
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::catch;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    fn context(predecessor: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id("curb.near".parse().unwrap())
+            .predecessor_account_id(predecessor)
+            .block_timestamp(1_000_000_000_000);
+        builder
+    }
+
+    fn general() -> Channel {
+        Channel {
+            name: "general".to_string(),
+        }
+    }
+
+    fn random() -> Channel {
+        Channel {
+            name: "random".to_string(),
+        }
+    }
+
+    /// Sends `text` to `group` as the current predecessor.
+    fn send_to(contract: &mut Curb, group: Channel, text: &str) {
+        contract.send_message(None, Some(group), text.to_string(), 1, None, None);
+    }
+
+    /// Sends `text` to #general as the current predecessor, in the thread of `parent` if set.
+    fn send(
+        contract: &mut Curb,
+        text: &str,
+        timestamp: u64,
+        parent: Option<MessageId>,
+    ) -> MessageId {
+        contract.send_message(
+            None,
+            Some(general()),
+            text.to_string(),
+            timestamp,
+            parent,
+            None,
+        );
+        Curb::get_message_id(
+            &env::predecessor_account_id(),
+            &None,
+            &Some(general()),
+            &text.into(),
+            timestamp,
+        )
+    }
+
+    #[test]
+    fn order_accounts_is_symmetric() {
+        let (alice, bob) = (accounts(0), accounts(1));
+        assert_eq!(
+            Curb::order_accounts(alice.clone(), bob.clone()),
+            (alice.clone(), bob.clone())
+        );
+        assert_eq!(
+            Curb::order_accounts(bob.clone(), alice.clone()),
+            (alice.clone(), bob)
+        );
+        assert_eq!(
+            Curb::order_accounts(alice.clone(), alice.clone()),
+            (alice.clone(), alice)
+        );
+    }
+
+    #[test]
+    fn message_id_is_deterministic_hex() {
+        testing_env!(context(accounts(0)).build());
+        let id = Curb::get_message_id(&accounts(0), &None, &Some(general()), &"hi".into(), 1);
+        assert_eq!(id.len(), 64);
+        assert!(id.bytes().all(|b| b.is_ascii_hexdigit()));
+        assert_eq!(
+            id,
+            Curb::get_message_id(&accounts(0), &None, &Some(general()), &"hi".into(), 1)
+        );
+    }
+
+    #[test]
+    fn message_id_depends_on_every_input() {
+        testing_env!(context(accounts(0)).build());
+        let id = Curb::get_message_id(&accounts(0), &None, &Some(general()), &"hi".into(), 1);
+        let others = [
+            Curb::get_message_id(&accounts(1), &None, &Some(general()), &"hi".into(), 1),
+            Curb::get_message_id(&accounts(0), &None, &Some(random()), &"hi".into(), 1),
+            Curb::get_message_id(&accounts(0), &Some(accounts(1)), &None, &"hi".into(), 1),
+            Curb::get_message_id(&accounts(0), &None, &Some(general()), &"ho".into(), 1),
+            Curb::get_message_id(&accounts(0), &None, &Some(general()), &"hi".into(), 2),
+        ];
+        for other in others.iter() {
+            assert_ne!(&id, other);
+        }
+    }
+
+    #[test]
+    fn first_join_creates_default_channel() {
+        // `new` is private, so on-chain the contract account itself initializes the instance.
+        testing_env!(context("curb.near".parse().unwrap()).build());
+        let mut contract = Curb::new("Calimero".to_string(), accounts(1));
+        assert_eq!(contract.get_owner(), &accounts(1));
+
+        testing_env!(context(accounts(0)).build());
+        contract.join();
+        assert!(contract.get_groups(None) == vec![&general()]);
+        assert!(contract.get_groups(Some(accounts(0))) == vec![&general()]);
+    }
+
+    #[test]
+    fn ownership_transfer_needs_acceptance() {
+        testing_env!(context(accounts(0)).build());
+        let mut contract = Curb::new("Calimero".to_string(), accounts(0));
+
+        testing_env!(context(accounts(1)).build());
+        assert_eq!(
+            catch(|| contract.transfer_ownership(accounts(1))),
+            Err(CurbError::NotTheOwner)
+        );
+        assert_eq!(
+            catch(|| contract.accept_ownership()),
+            Err(CurbError::NotThePendingOwner)
+        );
+
+        testing_env!(context(accounts(0)).build());
+        contract.transfer_ownership(accounts(1));
+        assert_eq!(contract.get_owner(), &accounts(0));
+
+        testing_env!(context(accounts(2)).build());
+        assert_eq!(
+            catch(|| contract.accept_ownership()),
+            Err(CurbError::NotThePendingOwner)
+        );
+
+        testing_env!(context(accounts(1)).build());
+        contract.accept_ownership();
+        assert_eq!(contract.get_owner(), &accounts(1));
+        assert_eq!(
+            catch(|| contract.accept_ownership()),
+            Err(CurbError::NotThePendingOwner)
+        );
+
+        testing_env!(context(accounts(0)).build());
+        assert_eq!(
+            catch(|| contract.set_name("Other".to_string())),
+            Err(CurbError::NotTheOwner)
+        );
+    }
+
+    #[test]
+    fn set_config_is_owner_only_and_validated() {
+        testing_env!(context(accounts(0)).build());
+        let mut contract = Curb::new("Calimero".to_string(), accounts(0));
+        contract.join();
+
+        testing_env!(context(accounts(1)).build());
+        assert_eq!(
+            catch(|| contract.set_config(Config::default())),
+            Err(CurbError::NotTheOwner)
+        );
+
+        testing_env!(context(accounts(0)).build());
+        let invalid = [
+            Config {
+                max_message_length: 0,
+                ..Config::default()
+            },
+            Config {
+                away_ms_threshold: ACTIVE_MS_THRESHOLD - 1,
+                ..Config::default()
+            },
+            Config {
+                max_poll_options: 1,
+                ..Config::default()
+            },
+            Config {
+                max_messages_per_tag: 0,
+                ..Config::default()
+            },
+        ];
+        for config in invalid {
+            assert_eq!(
+                catch(|| contract.set_config(config)),
+                Err(CurbError::InvalidConfig)
+            );
+        }
+        assert_eq!(
+            catch(|| contract.set_config(Config {
+                default_channel: random(),
+                ..Config::default()
+            })),
+            Err(CurbError::GroupDoesNotExist)
+        );
+        assert_eq!(
+            catch(|| contract.set_config(Config {
+                default_channel: Channel {
+                    name: "tab\tbed".to_string()
+                },
+                ..Config::default()
+            })),
+            Err(CurbError::GroupNameInvalidCharacters)
+        );
+
+        contract.create_group(random());
+        contract.set_config(Config {
+            default_channel: random(),
+            max_message_length: 10,
+            ..Config::default()
+        });
+        assert!(contract.get_config().default_channel == random());
+        assert_eq!(contract.get_config().max_message_length, 10);
+    }
+
+    #[test]
+    fn registration_follows_the_allowlist() {
+        testing_env!(context(accounts(0)).build());
+        let mut contract = Curb::new("Calimero".to_string(), accounts(0));
+        contract.set_config(Config {
+            registration: RegistrationMode::Closed,
+            ..Config::default()
+        });
+        contract.join();
+        testing_env!(context(accounts(1)).build());
+        assert_eq!(
+            catch(|| contract.join()),
+            Err(CurbError::RegistrationClosed)
+        );
+        assert_eq!(
+            catch(|| contract.add_to_allowlist(vec![accounts(1)])),
+            Err(CurbError::NotTheOwner)
+        );
+
+        testing_env!(context(accounts(0)).build());
+        contract.set_config(Config {
+            registration: RegistrationMode::Allowlist,
+            ..Config::default()
+        });
+        contract.add_to_allowlist(vec![accounts(1), accounts(2)]);
+        contract.remove_from_allowlist(vec![accounts(2)]);
+        assert_eq!(contract.get_allowlist(), vec![&accounts(1)]);
+        testing_env!(context(accounts(2)).build());
+        assert_eq!(catch(|| contract.join()), Err(CurbError::NotOnAllowlist));
+        testing_env!(context(accounts(1)).build());
+        contract.join();
+    }
+
+    #[test]
+    fn invites_are_bound_to_the_invitee_and_limited_by_quota() {
+        testing_env!(context(accounts(0)).build());
+        let mut contract = Curb::new("Calimero".to_string(), accounts(0));
+        contract.join();
+        testing_env!(context(accounts(1)).build());
+        contract.join();
+        testing_env!(context(accounts(0)).build());
+        contract.set_config(Config {
+            registration: RegistrationMode::Invite,
+            invite_quota: 1,
+            ..Config::default()
+        });
+
+        testing_env!(context(accounts(1)).build());
+        let first = Curb::invite_code_hash(&accounts(2), "first");
+        contract.create_invite(first.clone());
+        assert_eq!(contract.remaining_invites(accounts(1)), 0);
+        let second = Curb::invite_code_hash(&accounts(2), "second");
+        assert_eq!(
+            catch(|| contract.create_invite(second.clone())),
+            Err(CurbError::InviteQuotaExceeded)
+        );
+        contract.revoke_invite(first.clone());
+        contract.create_invite(second.to_ascii_uppercase());
+        assert_eq!(contract.get_invites(Some(accounts(1))), vec![&second]);
+        assert_eq!(
+            catch(|| contract.create_invite("0".repeat(63))),
+            Err(CurbError::InvalidInviteCodeHash)
+        );
+
+        testing_env!(context(accounts(3)).build());
+        assert_eq!(
+            catch(|| contract.join_with_invite("second".to_string())),
+            Err(CurbError::InvalidInviteCode)
+        );
+        assert_eq!(
+            catch(|| contract.revoke_invite(second.clone())),
+            Err(CurbError::NotInviteCreator)
+        );
+        testing_env!(context(accounts(2)).build());
+        assert_eq!(
+            catch(|| contract.join()),
+            Err(CurbError::InviteCodeRequired)
+        );
+        assert_eq!(
+            catch(|| contract.join_with_invite("first".to_string())),
+            Err(CurbError::InvalidInviteCode)
+        );
+        contract.join_with_invite("second".to_string());
+        assert!(contract.members.contains_key(&accounts(2)));
+        assert!(contract.get_invites(None).is_empty());
+    }
+
+    #[test]
+    fn token_bucket_refills_at_capacity_per_period() {
+        let mut bucket = TokenBucket::full(3, 0);
+        assert!(bucket.take(3, 3000, 0));
+        assert!(bucket.take(3, 3000, 0));
+        assert!(bucket.take(3, 3000, 0));
+        assert!(!bucket.take(3, 3000, 999));
+        assert_eq!(bucket.available(3, 3000, 999), 0);
+
+        // One token per second, the part of a second not yet refilled carries over.
+        assert!(bucket.take(3, 3000, 1500));
+        assert_eq!(bucket.updated_at, 1000);
+        assert_eq!(bucket.available(3, 3000, 1999), 0);
+        assert_eq!(bucket.available(3, 3000, 2000), 1);
+
+        // Refills never exceed the capacity, also after a long pause.
+        assert_eq!(bucket.available(3, 3000, u64::MAX), 3);
+        bucket.refill(3, 3000, 100_000);
+        assert_eq!((bucket.tokens, bucket.updated_at), (3, 100_000));
+
+        // A lowered capacity caps the stored tokens straight away.
+        assert_eq!(bucket.available(2, 3000, 100_000), 2);
+    }
+
+    #[test]
+    fn create_group_checks_membership_before_the_rate_limit() {
+        testing_env!(context(accounts(0)).build());
+        let mut contract = Curb::new("Calimero".to_string(), accounts(0));
+        contract.join();
+        let channels = contract.config.rate_limits.channels_per_day;
+
+        testing_env!(context(accounts(1)).build());
+        for _ in 0..=channels {
+            assert_eq!(
+                catch(|| contract.create_group(random())),
+                Err(CurbError::NotAMember)
+            );
+        }
+        assert_eq!(
+            contract.get_rate_limit_budget(accounts(1)).channels,
+            Some(channels)
+        );
+
+        testing_env!(context(accounts(0)).build());
+        for i in 0..channels {
+            contract.create_group(Channel {
+                name: format!("channel-{}", i),
+            });
+        }
+        assert_eq!(
+            contract.get_rate_limit_budget(accounts(0)).channels,
+            Some(0)
+        );
+        assert_eq!(
+            catch(|| contract.create_group(random())),
+            Err(CurbError::RateLimitExceeded)
+        );
+    }
+
+    #[test]
+    fn channel_names_are_normalized_at_every_entry_point() {
+        let composed = Channel {
+            name: "caf\u{e9}".to_string(),
+        };
+        let decomposed = || Channel {
+            name: "cafe\u{301}".to_string(),
+        };
+        testing_env!(context(accounts(0)).build());
+        let mut contract = Curb::new("Calimero".to_string(), accounts(0));
+        contract.join();
+        contract.create_group(decomposed());
+        assert!(contract.get_groups(None).contains(&&composed));
+
+        testing_env!(context(accounts(1)).build());
+        contract.join();
+        contract.join_group(decomposed());
+        assert_eq!(contract.get_members(Some(composed.clone())).len(), 2);
+        assert_eq!(
+            catch(|| contract.create_group(decomposed())),
+            Err(CurbError::GroupAlreadyExists)
+        );
+
+        contract.send_message(None, Some(decomposed()), "hi".to_string(), 1, None, None);
+        assert_eq!(
+            contract
+                .get_messages(None, Some(composed.clone()), None, None, None)
+                .len(),
+            1
+        );
+        assert!(contract.channel_info(decomposed()).is_some());
+
+        contract.leave_group(decomposed());
+        assert_eq!(contract.get_members(Some(composed)).len(), 1);
+    }
+
+    #[test]
+    fn leaving_last_member_deletes_channel_but_not_default() {
+        testing_env!(context(accounts(0)).build());
+        let mut contract = Curb::new("Calimero".to_string(), accounts(0));
+        contract.join();
+        contract.create_group(random());
+        assert!(contract.channel_info(random()).is_some());
+        contract.send_message(None, Some(random()), "hi".to_string(), 1, None, None);
+        let id = Curb::get_message_id(&accounts(0), &None, &Some(random()), &"hi".into(), 1);
+        contract.toggle_reaction(id.clone(), "👍".to_string());
+
+        contract.leave_group(random());
+        assert!(contract.channel_info(random()).is_none());
+        assert!(contract.message_locations.get(&id).is_none());
+        assert!(contract.reactions.get(&id).is_none());
+        contract.create_group(random());
+        assert_eq!(
+            catch(|| contract.toggle_reaction(id.clone(), "👍".to_string())),
+            Err(CurbError::MessageDoesNotExist)
+        );
+
+        contract.leave_group(general());
+        assert!(contract.channel_info(general()).is_some());
+    }
+
+    #[test]
+    fn leave_withdraws_the_account_and_refunds_its_deposit() {
+        testing_env!(context(accounts(0)).build());
+        let mut contract = Curb::new("Calimero".to_string(), accounts(0));
+        contract.join();
+        let poll = Poll {
+            question: "Lunch?".to_string(),
+            options: vec!["pizza".to_string(), "sushi".to_string()],
+            multiple_choice: false,
+            deadline: None,
+        };
+        contract.send_message(
+            None,
+            Some(general()),
+            "Lunch?".to_string(),
+            1,
+            None,
+            Some(MessageOptions {
+                poll: Some(poll),
+                ..Default::default()
+            }),
+        );
+        let question =
+            Curb::get_message_id(&accounts(0), &None, &Some(general()), &"Lunch?".into(), 1);
+
+        testing_env!(context(accounts(1))
+            .attached_deposit(10u128.pow(24))
+            .build());
+        contract.join();
+        let own = send(&mut contract, "mine", 2, None);
+        let reply = send(&mut contract, "also mine", 3, Some(question.clone()));
+        contract.send_message(Some(accounts(0)), None, "hi".to_string(), 4, None, None);
+        contract.toggle_reaction(question.clone(), "👍".to_string());
+        contract.vote(question.clone(), vec![1]);
+        // Write the cached state as the end of each call would, so the release is measurable.
+        contract.flush();
+        contract.leave(true);
+
+        assert!(!contract.members.contains_key(&accounts(1)));
+        assert!(!contract.channel_members[&general()].contains(&accounts(1)));
+        let general_info = &contract.channels[&general()];
+        let own = general_info.messages.iter().find(|m| m.id == own).unwrap();
+        assert!(own.deleted && own.text.is_empty());
+        assert!(contract.threads[&question][0].id == reply);
+        assert!(contract.threads[&question][0].text.is_empty());
+        let chat = Curb::order_accounts(accounts(0), accounts(1));
+        assert!(contract.chats[&chat].messages[0].deleted);
+        for location in [
+            MessageLocation::Channel(general()),
+            MessageLocation::Chat(chat.0, chat.1),
+        ] {
+            assert!(contract
+                .read_markers
+                .get(&(accounts(1), location))
+                .is_none());
+        }
+        assert!(contract.reactions.get(&question).is_none());
+        assert!(contract.reactors.is_empty());
+        let results = contract.get_poll(question, None).unwrap();
+        assert_eq!((results.votes, results.voters), (vec![0, 0], 0));
+        assert!(contract.account_reactions.get(&accounts(1)).is_none());
+        assert!(contract.account_ballots.get(&accounts(1)).is_none());
+        assert!(contract.sent_in.get(&accounts(1)).is_none());
+        assert!(contract.storage_deposits.get(&accounts(1)).is_none());
+        let refunds: Vec<_> = near_sdk::test_utils::get_created_receipts()
+            .into_iter()
+            .filter(|r| r.receiver_id == accounts(1))
+            .collect();
+        assert_eq!(refunds.len(), 1);
+        assert!(matches!(
+            refunds[0].actions[..],
+            [near_sdk::mock::VmAction::Transfer { deposit }] if deposit > 0
+        ));
+    }
+
+    #[test]
+    fn channel_moderation_is_limited_to_moderators() {
+        testing_env!(context(accounts(0)).build());
+        let mut contract = Curb::new("Calimero".to_string(), accounts(0));
+        contract.join();
+        testing_env!(context(accounts(1)).build());
+        contract.join();
+        contract.create_group(random());
+        testing_env!(context(accounts(2)).build());
+        contract.join();
+        contract.join_group(random());
+
+        assert_eq!(
+            catch(|| contract.kick(random(), accounts(1), None)),
+            Err(CurbError::NotAModerator)
+        );
+        assert_eq!(
+            catch(|| contract.mute(general(), accounts(1), 1, None)),
+            Err(CurbError::NotAModerator)
+        );
+
+        testing_env!(context(accounts(1)).build());
+        contract.mute(random(), accounts(2), MINUTE_MS, Some("spam".to_string()));
+        testing_env!(context(accounts(2)).build());
+        assert_eq!(
+            catch(|| send_to(&mut contract, random(), "hi")),
+            Err(CurbError::MutedInGroup)
+        );
+        testing_env!(context(accounts(2))
+            .block_timestamp(1_000_000_000_000 + (MINUTE_MS + 1) * 1_000_000)
+            .build());
+        send_to(&mut contract, random(), "hi");
+
+        testing_env!(context(accounts(1)).build());
+        contract.unmute(random(), accounts(2), None);
+        assert_eq!(
+            catch(|| contract.unmute(random(), accounts(2), None)),
+            Err(CurbError::NotMuted)
+        );
+        contract.kick(random(), accounts(2), None);
+        assert_eq!(
+            catch(|| contract.kick(random(), accounts(2), None)),
+            Err(CurbError::NotAGroupMember)
+        );
+        contract.ban(random(), accounts(2), None);
+        assert_eq!(
+            catch(|| contract.ban(random(), accounts(2), None)),
+            Err(CurbError::AlreadyBanned)
+        );
+        testing_env!(context(accounts(2)).build());
+        assert_eq!(
+            catch(|| contract.join_group(random())),
+            Err(CurbError::BannedFromGroup)
+        );
+        testing_env!(context(accounts(1)).build());
+        contract.unban(random(), accounts(2), None);
+        testing_env!(context(accounts(2)).build());
+        contract.join_group(random());
+
+        let log = contract.get_moderation_log(Some(random()), None, None);
+        assert_eq!(log.len(), 5);
+        assert_eq!(log[0].reason.as_deref(), Some("spam"));
+        assert!(log.iter().all(|entry| entry.moderator == accounts(1)));
+    }
+
+    #[test]
+    fn banned_members_are_removed_and_cannot_rejoin() {
+        testing_env!(context(accounts(0)).build());
+        let mut contract = Curb::new("Calimero".to_string(), accounts(0));
+        contract.join();
+        let first = send(&mut contract, "first", 1, None);
+
+        testing_env!(context(accounts(1))
+            .attached_deposit(10u128.pow(24))
+            .build());
+        contract.join();
+        let own = send(&mut contract, "mine", 2, None);
+        contract.toggle_reaction(first.clone(), "👍".to_string());
+        contract.flush();
+        assert_eq!(
+            catch(|| contract.ban_member(accounts(0), None)),
+            Err(CurbError::NotAModerator)
+        );
+
+        testing_env!(context(accounts(0)).build());
+        contract.ban_member(accounts(1), Some("spam".to_string()));
+        assert_eq!(
+            catch(|| contract.ban_member(accounts(1), None)),
+            Err(CurbError::AlreadyBanned)
+        );
+        assert!(!contract.members.contains_key(&accounts(1)));
+        assert!(!contract.channel_members[&general()].contains(&accounts(1)));
+        assert!(contract.reactions.get(&first).is_none());
+        assert!(contract.rate_limits.get(&accounts(1)).is_none());
+        assert!(contract.storage_deposits.get(&accounts(1)).is_none());
+        let location = MessageLocation::Channel(general());
+        assert!(contract
+            .read_markers
+            .get(&(accounts(1), location))
+            .is_none());
+        let messages = &contract.channels[&general()].messages;
+        assert!(!messages.iter().find(|m| m.id == own).unwrap().deleted);
+
+        testing_env!(context(accounts(1)).build());
+        assert_eq!(catch(|| contract.join()), Err(CurbError::AccountBanned));
+        testing_env!(context(accounts(0)).build());
+        contract.unban_member(accounts(1), None);
+        assert_eq!(
+            catch(|| contract.unban_member(accounts(1), None)),
+            Err(CurbError::NotBanned)
+        );
+        testing_env!(context(accounts(1)).build());
+        contract.join();
+    }
+
+    #[test]
+    fn thread_replies_are_read_per_thread() {
+        testing_env!(context(accounts(0)).build());
+        let mut contract = Curb::new("Calimero".to_string(), accounts(0));
+        contract.join();
+        let parent = send(&mut contract, "question", 1, None);
+
+        testing_env!(context(accounts(1)).build());
+        contract.join();
+        send(&mut contract, "answer", 2, Some(parent.clone()));
+        testing_env!(context(accounts(0)).build());
+        let follow_up = send(&mut contract, "thanks", 3, Some(parent.clone()));
+        send(&mut contract, "one more", 4, Some(parent.clone()));
+
+        let unread = contract.unread_messages(accounts(1));
+        assert_eq!(unread.threads[&parent].count, 2);
+        assert_eq!(
+            contract.unread_messages(accounts(0)).threads[&parent].count,
+            0
+        );
+
+        testing_env!(context(accounts(1)).build());
+        contract.read_message(None, Some(general()), follow_up.clone());
+        let unread = contract.unread_messages(accounts(1));
+        assert_eq!(unread.threads[&parent].count, 1);
+        assert_eq!(unread.threads[&parent].last_seen, Some(follow_up));
+        assert!(contract.unread_messages(accounts(2)).threads.is_empty());
+
+        contract.leave_group(general());
+        assert!(contract.unread_messages(accounts(1)).threads.is_empty());
+    }
+
+    #[test]
+    fn replies_and_forwards_resolve_previews() {
+        testing_env!(context(accounts(0)).build());
+        let mut contract = Curb::new("Calimero".to_string(), accounts(0));
+        contract.join();
+        let text = "a".repeat(PREVIEW_LENGTH + 50);
+        let original = send(&mut contract, &text, 1, None);
+        contract.send_message(
+            None,
+            Some(general()),
+            "reply".to_string(),
+            2,
+            None,
+            Some(MessageOptions {
+                reply_to: Some(original.clone()),
+                ..Default::default()
+            }),
+        );
+
+        testing_env!(context(accounts(1)).build());
+        contract.join();
+        contract.send_message(
+            Some(accounts(0)),
+            None,
+            String::new(),
+            3,
+            None,
+            Some(MessageOptions {
+                forwarded_from: Some(original.clone()),
+                ..Default::default()
+            }),
+        );
+
+        let channel = contract.get_messages(None, Some(general()), None, None, None);
+        assert!(channel[0].reply_to.is_none());
+        let quote = channel[1].reply_to.as_ref().unwrap();
+        assert_eq!(quote.id, original);
+        assert_eq!(quote.sender, accounts(0));
+        assert_eq!(quote.text, "a".repeat(PREVIEW_LENGTH));
+
+        let chat = contract.get_messages(Some((accounts(1), accounts(0))), None, None, None, None);
+        assert_eq!(chat[0].forwarded_from.as_ref().unwrap().id, original);
+
+        // Threads hang off top-level messages of the same conversation only.
+        testing_env!(context(accounts(0)).build());
+        let threaded = send(&mut contract, "threaded", 4, Some(original.clone()));
+        for parent in [threaded, chat[0].id.clone(), "missing".to_string()] {
+            assert_eq!(
+                catch(|| contract.send_message(
+                    None,
+                    Some(general()),
+                    "x".to_string(),
+                    5,
+                    Some(parent),
+                    None,
+                )),
+                Err(CurbError::InvalidParentMessage)
+            );
+        }
+    }
+
+    #[test]
+    fn votes_can_be_changed_and_withdrawn() {
+        testing_env!(context(accounts(0)).build());
+        let mut contract = Curb::new("Calimero".to_string(), accounts(0));
+        contract.join();
+        let poll = Poll {
+            question: "Lunch?".to_string(),
+            options: vec![
+                "pizza".to_string(),
+                "sushi".to_string(),
+                "salad".to_string(),
+            ],
+            multiple_choice: true,
+            deadline: None,
+        };
+        contract.send_message(
+            None,
+            Some(general()),
+            "Lunch?".to_string(),
+            1,
+            None,
+            Some(MessageOptions {
+                poll: Some(poll),
+                ..Default::default()
+            }),
+        );
+        let id = Curb::get_message_id(&accounts(0), &None, &Some(general()), &"Lunch?".into(), 1);
+        contract.vote(id.clone(), vec![2, 0]);
+
+        testing_env!(context(accounts(1)).build());
+        contract.join();
+        contract.vote(id.clone(), vec![0]);
+        contract.vote(id.clone(), vec![1]);
+        let results = contract.get_poll(id.clone(), Some(accounts(1))).unwrap();
+        assert_eq!((results.votes, results.voters), (vec![1, 1, 1], 2));
+        assert_eq!(results.my_votes, vec![1]);
+
+        contract.vote(id.clone(), vec![]);
+        let messages = contract.get_messages(None, Some(general()), None, None, Some(accounts(0)));
+        let results = messages[0].poll.as_ref().unwrap();
+        assert_eq!((&results.votes, results.voters), (&vec![1, 0, 1], 1));
+        assert_eq!(results.my_votes, vec![0, 2]);
+
+        contract.vote(id.clone(), vec![2]);
+        testing_env!(context(accounts(0)).build());
+        contract.ban_member(accounts(1), None);
+        let results = contract.get_poll(id.clone(), None).unwrap();
+        assert_eq!((results.votes, results.voters), (vec![1, 0, 1], 1));
+
+        contract.leave(true);
+        assert!(contract.poll_tallies.get(&id).is_none());
+        assert!(contract.account_ballots.get(&accounts(0)).is_none());
+    }
+
+    #[test]
+    fn expired_messages_are_hidden_then_removed() {
+        testing_env!(context(accounts(0)).build());
+        let mut contract = Curb::new("Calimero".to_string(), accounts(0));
+        contract.join();
+        contract.set_message_expiry(None, Some(general()), Some(10));
+        let expiring = send(&mut contract, "gone soon", 1, None);
+        contract.set_message_expiry(None, Some(general()), None);
+        let kept = send(&mut contract, "here to stay", 2, None);
+
+        testing_env!(context(accounts(1)).build());
+        contract.join();
+        contract.read_message(None, Some(general()), kept.clone());
+        assert_eq!(
+            contract.get_messages(None, Some(general()), None, None, None)[0].expires_at,
+            Some(1_000_010)
+        );
+
+        testing_env!(context(accounts(1))
+            .block_timestamp(1_000_020_000_000)
+            .build());
+        let messages = contract.get_messages(None, Some(general()), None, None, None);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, kept);
+        assert!(contract.get_poll(expiring.clone(), None).is_none());
+
+        assert_eq!(
+            contract.remove_expired_messages(None, Some(general()), None),
+            1
+        );
+        assert_eq!(
+            contract.remove_expired_messages(None, Some(general()), None),
+            0
+        );
+        let unread = &contract.unread_messages(accounts(1)).channels["general"];
+        assert_eq!((unread.count, unread.last_seen.as_ref()), (0, Some(&kept)));
+        assert_eq!(
+            contract
+                .message_counts
+                .get(&MessageLocation::Channel(general())),
+            Some(&1)
+        );
+    }
+
+    #[test]
+    fn expired_thread_replies_are_removed_and_refunded() {
+        testing_env!(context(accounts(0))
+            .attached_deposit(10u128.pow(24))
+            .build());
+        let mut contract = Curb::new("Calimero".to_string(), accounts(0));
+        contract.join();
+        let first = send(&mut contract, "first", 1, None);
+        let second = send(&mut contract, "second", 2, None);
+        let mut expiring = vec![];
+        for (timestamp, parent) in [(3, &first), (4, &second)] {
+            contract.send_message(
+                None,
+                Some(general()),
+                "gone soon".to_string(),
+                timestamp,
+                Some(parent.clone()),
+                Some(MessageOptions {
+                    expires_at: Some(1_000_010),
+                    ..Default::default()
+                }),
+            );
+            expiring.push(Curb::get_message_id(
+                &accounts(0),
+                &None,
+                &Some(general()),
+                &"gone soon".into(),
+                timestamp,
+            ));
+        }
+        let kept = send(&mut contract, "kept", 5, Some(first.clone()));
+        let deposit = contract.storage_deposits[&accounts(0)];
+
+        testing_env!(context(accounts(1))
+            .block_timestamp(1_000_020_000_000)
+            .build());
+        assert_eq!(
+            contract.remove_expired_messages(None, Some(general()), Some(1)),
+            1
+        );
+        assert_eq!(
+            contract.remove_expired_messages(None, Some(general()), None),
+            1
+        );
+        assert_eq!(
+            contract.remove_expired_messages(None, Some(general()), None),
+            0
+        );
+        for id in expiring.iter() {
+            assert!(contract.message_locations.get(id).is_none());
+        }
+        let thread: Vec<&MessageId> = contract.threads[&first].iter().map(|m| &m.id).collect();
+        assert_eq!(thread, [&kept]);
+        assert!(contract.threads.get(&second).is_none());
+        assert_eq!(
+            contract
+                .thread_parents
+                .get(&MessageLocation::Channel(general())),
+            Some(&vec![first])
+        );
+        assert!(contract.storage_deposits[&accounts(0)] < deposit);
+    }
+
+    #[test]
+    fn prune_applies_retention_in_batches() {
+        testing_env!(context(accounts(0)).build());
+        let mut contract = Curb::new("Calimero".to_string(), accounts(0));
+        contract.join();
+        let oldest = send(&mut contract, "one", 1, None);
+        let reply = send(&mut contract, "reply", 2, Some(oldest.clone()));
+        contract.toggle_reaction(oldest.clone(), "\u{1f44d}".to_string());
+        contract.toggle_reaction(reply.clone(), "\u{1f44d}".to_string());
+        send(&mut contract, "two", 3, None);
+        send(&mut contract, "three", 4, None);
+        // Ages count from when the contract received a message, not from its client timestamp.
+        testing_env!(context(accounts(0))
+            .block_timestamp(1_000_005_000_000)
+            .build());
+        let newest = send(&mut contract, "four", 5, None);
+        assert_eq!(contract.prune(general(), None), 0);
+
+        testing_env!(context(accounts(0))
+            .block_timestamp(1_000_012_000_000)
+            .build());
+        contract.set_retention(
+            general(),
+            RetentionPolicy {
+                max_messages: Some(3),
+                max_age_ms: Some(10),
+            },
+        );
+        assert_eq!(contract.prune(general(), Some(1)), 1);
+        assert!(contract.threads.get(&oldest).is_none());
+        for id in [&oldest, &reply] {
+            assert!(contract.reactions.get(id).is_none());
+            assert!(contract.message_locations.get(id).is_none());
+        }
+        assert!(contract
+            .reactors
+            .get(&(oldest.clone(), "\u{1f44d}".to_string()))
+            .is_none());
+
+        // Only the newest message is recent enough.
+        assert_eq!(contract.prune(general(), None), 2);
+        assert_eq!(contract.prune(general(), None), 0);
+        let messages = contract.get_messages(None, Some(general()), None, None, None);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, newest);
+        let unread = &contract.unread_messages(accounts(0)).channels["general"];
+        assert_eq!(
+            (unread.count, unread.last_seen.as_ref()),
+            (0, Some(&newest))
+        );
+    }
+
+    #[test]
+    fn tag_index_is_bounded_and_follows_removals() {
+        testing_env!(context(accounts(0)).build());
+        let mut contract = Curb::new("Calimero".to_string(), accounts(0));
+        contract.join();
+        let tagged = |contract: &Curb, tag: &str| -> Vec<MessageId> {
+            contract
+                .get_messages_by_tag(general(), tag.to_string(), None, None, None)
+                .messages
+                .into_iter()
+                .map(|m| m.id)
+                .collect()
+        };
+        send(&mut contract, "before #rust", 1, None);
+        assert!(tagged(&contract, "rust").is_empty());
+
+        let mut config = contract.get_config().clone();
+        config.tag_index = true;
+        config.max_messages_per_tag = 2;
+        config.max_tags_per_channel = 2;
+        contract.set_config(config);
+        let first = send(&mut contract, "#Rust and #near", 2, None);
+        send(&mut contract, "#rust in a thread", 3, Some(first.clone()));
+        let second = send(&mut contract, "more #rust", 4, None);
+        assert_eq!(tagged(&contract, "#RUST"), [second.clone(), first.clone()]);
+        assert_eq!(tagged(&contract, "near"), [first]);
+        let page = contract.get_messages_by_tag(general(), "rust".to_string(), None, Some(1), None);
+        assert_eq!(page.messages[0].id, second);
+        let before = page.next;
+        assert!(before.is_some());
+
+        // A newer message neither shifts the next page nor repeats the previous one, which only
+        // had `first` left before it was dropped from the bounded index.
+        let third = send(&mut contract, "#rust again", 5, None);
+        assert_eq!(tagged(&contract, "rust"), [third.clone(), second]);
+        let page = contract.get_messages_by_tag(general(), "rust".to_string(), before, None, None);
+        assert!(page.messages.is_empty() && page.next.is_none());
+
+        send(&mut contract, "#zig", 6, None);
+        assert!(tagged(&contract, "zig").is_empty());
+
+        contract.set_retention(
+            general(),
+            RetentionPolicy {
+                max_messages: Some(2),
+                max_age_ms: None,
+            },
+        );
+        assert_eq!(contract.prune(general(), None), 3);
+        assert!(tagged(&contract, "near").is_empty());
+        assert!(contract.channel_tags[&general()].get("near").is_none());
+        assert_eq!(tagged(&contract, "rust"), [third]);
+
+        // Emptying `near` made room for another tag.
+        let zig = send(&mut contract, "#zig again", 7, None);
+        assert_eq!(tagged(&contract, "zig"), [zig]);
+    }
+
+    #[test]
+    fn messages_received_after_follow_receive_order() {
+        testing_env!(context(accounts(0)).build());
+        let mut contract = Curb::new("Calimero".to_string(), accounts(0));
+        contract.join();
+        let received = |contract: &Curb, after: Option<(u64, MessageId)>, length| {
+            contract
+                .get_messages_received_after(general(), after, length)
+                .into_iter()
+                .map(|m| (m.parent, m.message.id))
+                .collect::<Vec<_>>()
+        };
+        let first = send(&mut contract, "first", 5, None);
+        let reply = send(&mut contract, "reply", 6, Some(first.clone()));
+        testing_env!(context(accounts(0))
+            .block_timestamp(1_000_005_000_000)
+            .build());
+        // Sent later with an earlier client timestamp, it is still listed after the others,
+        // while messages received in the same block are ordered by id.
+        let late = send(&mut contract, "late", 1, None);
+        let mut same_block = vec![(None, first.clone()), (Some(first), reply)];
+        same_block.sort_by(|a, b| a.1.cmp(&b.1));
+        let mut expected = same_block.clone();
+        expected.push((None, late.clone()));
+        assert_eq!(received(&contract, None, None), expected);
+
+        let page = contract.get_messages_received_after(general(), None, Some(1));
+        let last = &page[0].message;
+        let cursor = Some((last.received_at, last.id.clone()));
+        assert_eq!(received(&contract, cursor, None), expected[1..]);
+        let after_late = Some((1_000_005, late));
+        assert!(received(&contract, after_late.clone(), None).is_empty());
+        testing_env!(context(accounts(0))
+            .block_timestamp(1_000_006_000_000)
+            .build());
+        let newest = send(&mut contract, "newest", 2, None);
+        assert_eq!(received(&contract, after_late, None), [(None, newest)]);
+        let missing = Channel {
+            name: "missing".to_string(),
+        };
+        assert!(contract
+            .get_messages_received_after(missing, None, None)
+            .is_empty());
+    }
+
+    #[test]
+    fn messages_received_after_page_through_threads_and_skip_removed() {
+        testing_env!(context(accounts(0)).build());
+        let mut contract = Curb::new("Calimero".to_string(), accounts(0));
+        contract.join();
+        // One message per block, with client timestamps out of receive order.
+        let mut sent: Vec<(Option<MessageId>, MessageId)> = vec![];
+        for (second, (text, timestamp, parent)) in [
+            ("a", 60, None),
+            ("a1", 61, Some(0)),
+            ("b", 10, None),
+            ("b1", 11, Some(2)),
+            ("c", 30, None),
+            ("a2", 62, Some(0)),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            testing_env!(context(accounts(0))
+                .block_timestamp(1_000_000_000_000 + second as u64 * 1_000_000_000)
+                .build());
+            let parent: Option<MessageId> = parent.map(|i: usize| sent[i].1.clone());
+            let id = send(&mut contract, text, timestamp, parent.clone());
+            sent.push((parent, id));
+        }
+        let page = |contract: &Curb, after: Option<(u64, MessageId)>| {
+            contract.get_messages_received_after(general(), after, Some(2))
+        };
+        let all = |contract: &Curb, mut after: Option<(u64, MessageId)>| {
+            let mut all = vec![];
+            loop {
+                let page = page(contract, after);
+                after = page
+                    .last()
+                    .map(|m| (m.message.received_at, m.message.id.clone()));
+                all.extend(page.into_iter().map(|m| (m.parent, m.message.id)));
+                if after.is_none() {
+                    return all;
+                }
+            }
+        };
+        assert_eq!(all(&contract, None), sent);
+
+        // Pruning `b` and its reply leaves their entries behind, but they are skipped, also when
+        // the cursor points at one of them.
+        contract.set_retention(
+            general(),
+            RetentionPolicy {
+                max_messages: Some(2),
+                max_age_ms: None,
+            },
+        );
+        assert_eq!(contract.prune(general(), None), 1);
+        let kept = [&sent[0], &sent[1], &sent[4], &sent[5]];
+        assert_eq!(
+            all(&contract, None),
+            kept.iter().map(|m| (*m).clone()).collect::<Vec<_>>()
+        );
+        let at_b = Some((1_002_000, sent[2].1.clone()));
+        assert_eq!(all(&contract, at_b), [sent[4].clone(), sent[5].clone()]);
+    }
+
+    #[test]
+    fn presence_follows_status_and_activity() {
+        testing_env!(context(accounts(0)).build());
+        let mut contract = Curb::new("Calimero".to_string(), accounts(0));
+        contract.join();
+        contract.set_status(
+            Presence::DoNotDisturb,
+            Some(" focusing ".to_string()),
+            Some(1_100_000),
+        );
+        let at = |contract: &Curb, ms: u64| {
+            testing_env!(context(accounts(1)).block_timestamp(ms * 1_000_000).build());
+            contract.get_members(None).remove(0)
+        };
+        let member = at(&contract, 1_040_000);
+        assert_eq!(member.presence, Presence::DoNotDisturb);
+        assert_eq!(member.status_text.as_deref(), Some("focusing"));
+        assert_eq!(member.last_seen, 1_000_000);
+        assert!(!member.active);
+
+        // The status expired and the member went idle.
+        let member = at(&contract, 1_200_000);
+        assert_eq!(
+            (member.presence, member.status_text),
+            (Presence::Away, None)
+        );
+        assert_eq!(at(&contract, 1_600_000).presence, Presence::Offline);
+
+        testing_env!(context(accounts(0)).build());
+        contract.set_status(Presence::Offline, None, None);
+        let member = at(&contract, 1_000_000);
+        assert_eq!(member.presence, Presence::Offline);
+        assert!(!member.active);
+
+        testing_env!(context(accounts(0)).build());
+        contract.set_status(Presence::Online, None, None);
+        assert!(contract.statuses.get(&accounts(0)).is_none());
+        let member = at(&contract, 1_000_000);
+        assert_eq!(member.presence, Presence::Online);
+        assert!(member.active);
+    }
+}
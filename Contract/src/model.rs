@@ -0,0 +1,529 @@
+//! Randomized state-machine test: random sequences of calls are applied both to `Curb` and to a
+//! minimal reference model, and the contract's internal invariants and its agreement with the
+//! model are checked after every step.
+//!
+//! Every call runs like a transaction on the contract stored in the mocked runtime: a failing call
+//! is caught and the storage restored to what it was before, as the runtime reverts a failed
+//! receipt. Invalid calls are generated on purpose and the model predicts the `CurbError` each one
+//! fails with.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use near_sdk::test_utils::{accounts, VMContextBuilder};
+use near_sdk::{env, AccountId, MockedBlockchain, RuntimeFeesConfig, VMConfig};
+use proptest::prelude::*;
+use proptest::sample::Index;
+
+use crate::error::catch;
+use crate::{Channel, ChannelInfo, Config, Curb, CurbError, MessageId, MessageLocation};
+
+const ACCOUNTS: usize = 5;
+const CHANNELS: [&str; 3] = ["general", "random", "dev"];
+const REACTIONS: [&str; 2] = ["\u{1f44d}", "\u{1f389}"];
+
+#[derive(Clone, Debug)]
+enum Op {
+    Join(usize),
+    CreateGroup(usize, usize),
+    JoinGroup(usize, usize),
+    LeaveGroup(usize, usize),
+    GroupInvite(usize, usize, usize),
+    SendToGroup(usize, usize, Option<Index>),
+    SendToAccount(usize, usize, Option<Index>),
+    ToggleReaction(usize, Index, usize),
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    let account = 0..ACCOUNTS;
+    let channel = 0..CHANNELS.len();
+    prop_oneof![
+        account.clone().prop_map(Op::Join),
+        (account.clone(), channel.clone()).prop_map(|(a, c)| Op::CreateGroup(a, c)),
+        (account.clone(), channel.clone()).prop_map(|(a, c)| Op::JoinGroup(a, c)),
+        (account.clone(), channel.clone()).prop_map(|(a, c)| Op::LeaveGroup(a, c)),
+        (account.clone(), channel.clone(), account.clone())
+            .prop_map(|(a, c, b)| Op::GroupInvite(a, c, b)),
+        (account.clone(), channel, any::<Option<Index>>())
+            .prop_map(|(a, c, p)| Op::SendToGroup(a, c, p)),
+        (account.clone(), account.clone(), any::<Option<Index>>())
+            .prop_map(|(a, b, p)| Op::SendToAccount(a, b, p)),
+        (account, any::<Index>(), 0..REACTIONS.len())
+            .prop_map(|(a, m, r)| Op::ToggleReaction(a, m, r)),
+    ]
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum Container {
+    Channel(String),
+    Chat(AccountId, AccountId),
+}
+
+struct ModelMessage {
+    id: MessageId,
+    container: Container,
+    parent: Option<MessageId>,
+}
+
+#[derive(Default)]
+struct Model {
+    members: BTreeSet<AccountId>,
+    channels: BTreeMap<String, BTreeSet<AccountId>>,
+    messages: Vec<ModelMessage>,
+    /// Messages of deleted channels, which calls may still refer to.
+    removed: Vec<MessageId>,
+    reactions: BTreeMap<(MessageId, String), BTreeSet<AccountId>>,
+}
+
+impl Model {
+    fn is_member(&self, account: &AccountId) -> bool {
+        self.members.contains(account)
+    }
+
+    /// Error of joining, leaving or inviting to channel `c` as `account`.
+    fn group_error(&self, c: usize, account: &AccountId) -> Option<CurbError> {
+        if !self.channels.contains_key(CHANNELS[c]) {
+            Some(CurbError::GroupDoesNotExist)
+        } else if !self.is_member(account) {
+            Some(CurbError::NotAMember)
+        } else {
+            None
+        }
+    }
+
+    fn in_channel(&self, channel: &str, account: &AccountId) -> bool {
+        self.channels
+            .get(channel)
+            .is_some_and(|members| members.contains(account))
+    }
+
+    fn top_level(&self, container: &Container) -> Vec<&ModelMessage> {
+        self.messages
+            .iter()
+            .filter(|m| m.parent.is_none() && &m.container == container)
+            .collect()
+    }
+
+    /// Any message id the model has seen, removed ones included, to reference from a call.
+    fn pick(&self, index: Index) -> Option<MessageId> {
+        let ids: Vec<&MessageId> = self
+            .messages
+            .iter()
+            .map(|m| &m.id)
+            .chain(self.removed.iter())
+            .collect();
+        (!ids.is_empty()).then(|| index.get(&ids).to_string())
+    }
+
+    /// Error of sending a reply to `parent` in `container`.
+    fn parent_error(&self, container: &Container, parent: &Option<MessageId>) -> Option<CurbError> {
+        let parent = parent.as_ref()?;
+        let valid = self.top_level(container).iter().any(|m| &m.id == parent);
+        (!valid).then_some(CurbError::InvalidParentMessage)
+    }
+
+    fn leave_channel(&mut self, channel: &str, account: &AccountId) {
+        let members = self.channels.get_mut(channel).unwrap();
+        members.remove(account);
+        if members.is_empty() && channel != CHANNELS[0] {
+            self.channels.remove(channel);
+            let container = Container::Channel(channel.to_string());
+            let (removed, kept): (Vec<ModelMessage>, _) = std::mem::take(&mut self.messages)
+                .into_iter()
+                .partition(|m| m.container == container);
+            self.messages = kept;
+            self.reactions
+                .retain(|(id, _), _| !removed.iter().any(|m| &m.id == id));
+            self.removed.extend(removed.into_iter().map(|m| m.id));
+        }
+    }
+}
+
+fn set_context(predecessor: &AccountId, step: u64, storage: HashMap<Vec<u8>, Vec<u8>>) {
+    let context = VMContextBuilder::new()
+        .current_account_id("curb.near".parse().unwrap())
+        .predecessor_account_id(predecessor.clone())
+        .block_timestamp(step * 1_000_000_000)
+        .build();
+    env::set_blockchain_interface(MockedBlockchain::new(
+        context,
+        VMConfig::test(),
+        RuntimeFeesConfig::test(),
+        vec![],
+        storage,
+        HashMap::new(),
+        None,
+    ));
+}
+
+fn take_storage() -> HashMap<Vec<u8>, Vec<u8>> {
+    near_sdk::mock::with_mocked_blockchain(|b| b.take_storage())
+}
+
+/// Runs `call` as `account` on the stored contract and writes its state back, or restores the
+/// storage when the call panics.
+fn transact(account: &AccountId, step: u64, call: impl FnOnce(&mut Curb)) -> Result<(), CurbError> {
+    let storage = take_storage();
+    set_context(account, step, storage.clone());
+    let mut contract: Curb = env::state_read().unwrap();
+    let result = catch(|| call(&mut contract));
+    if result.is_ok() {
+        env::state_write(&contract);
+    }
+    // Dropping the contract writes its cached collections.
+    drop(contract);
+    if result.is_err() {
+        set_context(account, step, storage);
+    }
+    result
+}
+
+/// Runs `call` and checks it fails with `error`, or succeeds without one. Returns whether the
+/// call took effect.
+fn run(
+    account: &AccountId,
+    step: u64,
+    error: Option<CurbError>,
+    call: impl FnOnce(&mut Curb),
+) -> bool {
+    assert_eq!(transact(account, step, call).err(), error);
+    error.is_none()
+}
+
+fn channel(index: usize) -> Channel {
+    Channel {
+        name: CHANNELS[index].to_string(),
+    }
+}
+
+/// Applies `op` to the contract, and to the model when the model expects it to succeed.
+fn apply(model: &mut Model, op: &Op, step: u64) {
+    let text = format!("message {}", step);
+    match op.clone() {
+        Op::Join(a) => {
+            let account = accounts(a);
+            let error = model
+                .is_member(&account)
+                .then_some(CurbError::AlreadyAMember);
+            if !run(&account, step, error, |c| c.join()) {
+                return;
+            }
+            if model.members.is_empty() {
+                model
+                    .channels
+                    .insert(CHANNELS[0].to_string(), BTreeSet::new());
+            }
+            model.members.insert(account.clone());
+            model.channels.get_mut(CHANNELS[0]).unwrap().insert(account);
+        }
+        Op::CreateGroup(a, c) => {
+            let account = accounts(a);
+            let error = if !model.is_member(&account) {
+                Some(CurbError::NotAMember)
+            } else if model.channels.contains_key(CHANNELS[c]) {
+                Some(CurbError::GroupAlreadyExists)
+            } else {
+                None
+            };
+            if run(&account, step, error, |contract| {
+                contract.create_group(channel(c))
+            }) {
+                model
+                    .channels
+                    .insert(CHANNELS[c].to_string(), BTreeSet::from([account]));
+            }
+        }
+        Op::JoinGroup(a, c) => {
+            let account = accounts(a);
+            if run(&account, step, model.group_error(c, &account), |contract| {
+                contract.join_group(channel(c))
+            }) {
+                model.channels.get_mut(CHANNELS[c]).unwrap().insert(account);
+            }
+        }
+        Op::LeaveGroup(a, c) => {
+            let account = accounts(a);
+            if run(&account, step, model.group_error(c, &account), |contract| {
+                contract.leave_group(channel(c))
+            }) {
+                model.leave_channel(CHANNELS[c], &account);
+            }
+        }
+        Op::GroupInvite(a, c, b) => {
+            let (account, invitee) = (accounts(a), accounts(b));
+            let error = model
+                .group_error(c, &account)
+                .or_else(|| (!model.is_member(&invitee)).then_some(CurbError::NotAMember));
+            if run(&account, step, error, |contract| {
+                contract.group_invite(channel(c), invitee.clone())
+            }) {
+                model.channels.get_mut(CHANNELS[c]).unwrap().insert(invitee);
+            }
+        }
+        Op::SendToGroup(a, c, parent) => {
+            let account = accounts(a);
+            let container = Container::Channel(CHANNELS[c].to_string());
+            let parent = parent.and_then(|p| model.pick(p));
+            let error = if !model.is_member(&account) {
+                Some(CurbError::NotAMember)
+            } else if !model.channels.contains_key(CHANNELS[c]) {
+                Some(CurbError::GroupDoesNotExist)
+            } else if !model.in_channel(CHANNELS[c], &account) {
+                Some(CurbError::NotAGroupMember)
+            } else {
+                model.parent_error(&container, &parent)
+            };
+            if run(&account, step, error, |contract| {
+                contract.send_message(
+                    None,
+                    Some(channel(c)),
+                    text.clone(),
+                    step,
+                    parent.clone(),
+                    None,
+                )
+            }) {
+                model.messages.push(ModelMessage {
+                    id: Curb::get_message_id(&account, &None, &Some(channel(c)), &text, step),
+                    container,
+                    parent,
+                });
+            }
+        }
+        Op::SendToAccount(a, b, parent) => {
+            let (account, other) = (accounts(a), accounts(b));
+            let (first, second) = Curb::order_accounts(account.clone(), other.clone());
+            let container = Container::Chat(first, second);
+            let parent = parent.and_then(|p| model.pick(p));
+            let error = if !model.is_member(&account) {
+                Some(CurbError::NotAMember)
+            } else if !model.is_member(&other) {
+                Some(CurbError::OtherAccountNotAMember)
+            } else {
+                model.parent_error(&container, &parent)
+            };
+            if run(&account, step, error, |contract| {
+                contract.send_message(
+                    Some(other.clone()),
+                    None,
+                    text.clone(),
+                    step,
+                    parent.clone(),
+                    None,
+                )
+            }) {
+                model.messages.push(ModelMessage {
+                    id: Curb::get_message_id(&account, &Some(other), &None, &text, step),
+                    container,
+                    parent,
+                });
+            }
+        }
+        Op::ToggleReaction(a, m, r) => {
+            let account = accounts(a);
+            let Some(message_id) = model.pick(m) else {
+                return;
+            };
+            let container = model
+                .messages
+                .iter()
+                .find(|m| m.id == message_id)
+                .map(|m| &m.container);
+            let error = if !model.is_member(&account) {
+                Some(CurbError::NotAMember)
+            } else {
+                match container {
+                    None => Some(CurbError::MessageDoesNotExist),
+                    Some(Container::Channel(channel)) => {
+                        (!model.in_channel(channel, &account)).then_some(CurbError::NotAGroupMember)
+                    }
+                    Some(Container::Chat(first, second)) => (first != &account
+                        && second != &account)
+                        .then_some(CurbError::MessageDoesNotExist),
+                }
+            };
+            if !run(&account, step, error, |contract| {
+                contract.toggle_reaction(message_id.clone(), REACTIONS[r].to_string())
+            }) {
+                return;
+            }
+            let reactors = model
+                .reactions
+                .entry((message_id, REACTIONS[r].to_string()))
+                .or_default();
+            if !reactors.remove(&account) {
+                reactors.insert(account);
+            }
+        }
+    }
+}
+
+/// Checks every account's read marker, not only those `last_read` iterates over, as lookups can
+/// also hit entries left behind under the same storage prefix.
+fn assert_last_read_exists(info: &ChannelInfo) {
+    for account in (0..ACCOUNTS).map(accounts) {
+        if let Some(message_id) = info.last_read.get(&account) {
+            assert!(
+                info.messages.iter().any(|m| &m.id == message_id),
+                "last_read of {} points at missing message {}",
+                account,
+                message_id
+            );
+        }
+    }
+}
+
+fn check_invariants(contract: &Curb, model: &Model) {
+    // `channel_members` and `member_channels` mirror each other.
+    for (channel, members) in contract.channel_members.iter() {
+        assert!(contract.channels.contains_key(channel));
+        for account in members.iter() {
+            assert!(
+                contract
+                    .member_channels
+                    .get(account)
+                    .is_some_and(|channels| channels.contains(channel)),
+                "{} is in #{} but the channel is not in its member_channels",
+                account,
+                channel.name
+            );
+        }
+    }
+    for (account, channels) in contract.member_channels.iter() {
+        assert!(contract.members.contains_key(account));
+        for channel in channels.iter() {
+            assert!(
+                contract
+                    .channel_members
+                    .get(channel)
+                    .is_some_and(|members| members.contains(account)),
+                "#{} is in member_channels of {} but it is not a channel member",
+                channel.name,
+                account
+            );
+        }
+    }
+    assert_eq!(contract.channels.len(), contract.channel_members.len());
+    assert_eq!(contract.members.len(), contract.member_channels.len());
+
+    // `last_read` points at existing messages.
+    for (_, info) in contract.channels.iter() {
+        assert_last_read_exists(info);
+    }
+    for (_, info) in contract.chats.iter() {
+        assert_last_read_exists(info);
+    }
+
+    // `chats` keys are ordered by `order_accounts`.
+    for ((first, second), _) in contract.chats.iter() {
+        assert_eq!(
+            Curb::order_accounts(second.clone(), first.clone()),
+            (first.clone(), second.clone())
+        );
+    }
+
+    // Message counters and the DM partner index follow the conversations.
+    for (channel, info) in contract.channels.iter() {
+        let location = MessageLocation::Channel(channel.clone());
+        let count = contract.message_counts.get(&location).copied().unwrap_or(0);
+        assert_eq!(count, info.messages.len() as u64);
+    }
+    for ((first, second), info) in contract.chats.iter() {
+        let location = MessageLocation::Chat(first.clone(), second.clone());
+        let count = contract.message_counts.get(&location).copied().unwrap_or(0);
+        assert_eq!(count, info.messages.len() as u64);
+        for (account, partner) in [(first, second), (second, first)] {
+            assert!(contract
+                .member_chats
+                .get(account)
+                .is_some_and(|partners| partners.contains(partner)));
+        }
+    }
+
+    // Unread counts cover exactly the channels the account is in and follow its read marker.
+    for account in model.members.iter() {
+        let unread = contract.unread_messages(account.clone());
+        let channels: BTreeSet<String> = unread.channels.keys().cloned().collect();
+        let joined: BTreeSet<String> = model
+            .channels
+            .iter()
+            .filter(|(_, members)| members.contains(account))
+            .map(|(name, _)| name.clone())
+            .collect();
+        assert_eq!(channels, joined);
+        for (name, unread) in unread.channels {
+            let info = contract.channels.get(&Channel { name }).unwrap();
+            let read = match info.last_read.get(account) {
+                Some(id) => info.messages.iter().position(|m| &m.id == id).unwrap() + 1,
+                None => 0,
+            };
+            assert_eq!(unread.count, info.messages.len() - read);
+            assert_eq!(unread.last_seen.as_ref(), info.last_read.get(account));
+        }
+    }
+
+    // Reaction counters match the reactors, which match the model.
+    for ((message_id, reaction), reactors) in model.reactions.iter() {
+        let count = contract
+            .reactions
+            .get(message_id)
+            .and_then(|counts| counts.iter().find(|c| &c.reaction == reaction))
+            .map_or(0, |c| c.count);
+        assert_eq!(count as usize, reactors.len());
+        let stored: BTreeSet<AccountId> = contract
+            .get_reactors(message_id.clone(), reaction.clone(), None, None)
+            .into_iter()
+            .cloned()
+            .collect();
+        assert_eq!(&stored, reactors);
+    }
+
+    // The contract agrees with the model.
+    let members: BTreeSet<AccountId> = contract.members.keys().cloned().collect();
+    assert_eq!(members, model.members);
+    let channels: BTreeMap<String, BTreeSet<AccountId>> = contract
+        .channel_members
+        .iter()
+        .map(|(channel, members)| (channel.name.clone(), members.iter().cloned().collect()))
+        .collect();
+    assert_eq!(channels, model.channels);
+    for (channel, info) in contract.channels.iter() {
+        let container = Container::Channel(channel.name.clone());
+        assert_eq!(info.messages.len(), model.top_level(&container).len());
+    }
+    for ((first, second), info) in contract.chats.iter() {
+        let container = Container::Chat(first.clone(), second.clone());
+        assert_eq!(info.messages.len(), model.top_level(&container).len());
+    }
+    for message in model.messages.iter().filter(|m| m.parent.is_none()) {
+        let replies = model
+            .messages
+            .iter()
+            .filter(|m| m.parent.as_ref() == Some(&message.id))
+            .count();
+        let thread = contract.threads.get(&message.id).map_or(0, Vec::len);
+        assert_eq!(thread, replies);
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(256))]
+
+    #[test]
+    fn contract_matches_model(ops in prop::collection::vec(op(), 1..80)) {
+        set_context(&accounts(0), 0, HashMap::new());
+        let mut contract = Curb::new("Calimero".to_string(), accounts(0));
+        let mut config: Config = contract.get_config().clone();
+        config.rate_limits.messages_per_minute = 0;
+        config.rate_limits.reactions_per_minute = 0;
+        config.rate_limits.channels_per_day = 0;
+        contract.set_config(config);
+        env::state_write(&contract);
+        drop(contract);
+
+        let mut model = Model::default();
+        for (step, op) in ops.iter().enumerate() {
+            apply(&mut model, op, step as u64 + 1);
+            check_invariants(&env::state_read().unwrap(), &model);
+        }
+    }
+}
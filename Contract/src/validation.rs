@@ -0,0 +1,622 @@
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::error::CurbError;
+use crate::{Attachment, LinkPreview, MessageFormat, Poll};
+
+/// Upper bound on the encoded size of a reaction regardless of its grapheme count, so a single
+/// grapheme with an unbounded number of combining marks can not be used as a key.
+const MAX_REACTION_BYTES: usize = 64;
+/// Upper bound on each text field of an attachment, so metadata can not be used to store content.
+const MAX_ATTACHMENT_FIELD_BYTES: usize = 512;
+const MAX_CODE_LANGUAGE_BYTES: usize = 32;
+const MAX_URL_BYTES: usize = 2048;
+const MAX_PREVIEW_TITLE_BYTES: usize = 256;
+const MAX_PREVIEW_DESCRIPTION_BYTES: usize = 1024;
+const MAX_POLL_QUESTION_BYTES: usize = 512;
+const MAX_POLL_OPTION_BYTES: usize = 128;
+const MAX_TAG_CHARS: usize = 32;
+const MAX_STATUS_TEXT_BYTES: usize = 128;
+
+pub fn normalize(text: &str) -> String {
+    text.nfc().collect()
+}
+
+/// Normalizes a channel name and checks it is not blank, contains no control characters and is
+/// at most `max_length` characters long.
+pub fn channel_name(name: &str, max_length: u32) -> Result<String, CurbError> {
+    let name = normalize(name);
+    if name.trim().is_empty() {
+        return Err(CurbError::GroupNameEmpty);
+    }
+    if name.chars().any(char::is_control) {
+        return Err(CurbError::GroupNameInvalidCharacters);
+    }
+    if name.chars().count() > max_length as usize {
+        return Err(CurbError::GroupNameTooLong);
+    }
+    Ok(name)
+}
+
+/// Normalizes a message text and checks it is at most `max_length` bytes long.
+pub fn message(text: &str, max_length: u32) -> Result<String, CurbError> {
+    let text = normalize(text);
+    if text.len() > max_length as usize {
+        return Err(CurbError::MessageTooLong);
+    }
+    Ok(text)
+}
+
+/// Normalizes a reaction and checks it is at most `max_graphemes` user-perceived characters and,
+/// when `allowed` is set, one of the allowed reactions.
+pub fn reaction(
+    reaction: &str,
+    max_graphemes: u32,
+    allowed: &Option<Vec<String>>,
+) -> Result<String, CurbError> {
+    let reaction = normalize(reaction);
+    if reaction.is_empty() {
+        return Err(CurbError::ReactionEmpty);
+    }
+    if reaction.len() > MAX_REACTION_BYTES
+        || reaction.graphemes(true).count() > max_graphemes as usize
+    {
+        return Err(CurbError::ReactionTooLong);
+    }
+    if let Some(allowed) = allowed {
+        if !allowed.contains(&reaction) {
+            return Err(CurbError::ReactionNotAllowed);
+        }
+    }
+    Ok(reaction)
+}
+
+/// Checks there are at most `max_count` attachments of at most `max_size` bytes each, with a
+/// `type/subtype` MIME type and non-blank, bounded fields free of control characters. Filenames
+/// are normalized and may not contain path separators.
+pub fn attachments(
+    attachments: Vec<Attachment>,
+    max_count: u32,
+    max_size: u64,
+) -> Result<Vec<Attachment>, CurbError> {
+    if attachments.len() > max_count as usize {
+        return Err(CurbError::TooManyAttachments);
+    }
+    attachments
+        .into_iter()
+        .map(|mut attachment| {
+            if attachment.size > max_size {
+                return Err(CurbError::AttachmentTooLarge);
+            }
+            attachment.filename = normalize(&attachment.filename);
+            let field_ok = |field: &str| {
+                !field.trim().is_empty()
+                    && field.len() <= MAX_ATTACHMENT_FIELD_BYTES
+                    && !field.chars().any(char::is_control)
+            };
+            let token_ok = |field: &str| field_ok(field) && !field.contains(char::is_whitespace);
+            let mime_ok = token_ok(&attachment.mime_type)
+                && matches!(
+                    attachment.mime_type.split_once('/'),
+                    Some((kind, subtype))
+                        if !kind.is_empty() && !subtype.is_empty() && !subtype.contains('/')
+                );
+            let valid = mime_ok
+                && token_ok(&attachment.content_hash)
+                && token_ok(&attachment.uri)
+                && field_ok(&attachment.filename)
+                && !attachment.filename.contains(['/', '\\'])
+                && attachment.thumbnail_hash.as_deref().is_none_or(token_ok);
+            if !valid {
+                return Err(CurbError::InvalidAttachment);
+            }
+            Ok(attachment)
+        })
+        .collect()
+}
+
+/// Checks the language of a code block is a short identifier such as `rust` or `c++`, lower-cased
+/// so clients can match it against their highlighters.
+pub fn format(format: MessageFormat) -> Result<MessageFormat, CurbError> {
+    match format {
+        MessageFormat::Code {
+            language: Some(language),
+        } => {
+            if language.is_empty()
+                || language.len() > MAX_CODE_LANGUAGE_BYTES
+                || !language
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+#-_.".contains(c))
+            {
+                return Err(CurbError::InvalidCodeLanguage);
+            }
+            Ok(MessageFormat::Code {
+                language: Some(language.to_ascii_lowercase()),
+            })
+        }
+        format => Ok(format),
+    }
+}
+
+/// Whether `url` appears in `text` as a whole whitespace separated word, optionally wrapped in
+/// brackets, quotes or followed by punctuation, so `https://near.org` does not match
+/// `https://near.org.evil.com`.
+fn contains_link(text: &str, url: &str) -> bool {
+    text.split_whitespace().any(|word| {
+        word == url
+            || word
+                .trim_start_matches(['(', '<', '[', '"', '\''])
+                .trim_end_matches([')', '>', ']', '"', '\'', '.', ',', ';', ':', '!', '?'])
+                == url
+    })
+}
+
+/// Checks there are at most `max_count` previews, each of a distinct `http(s)` link found in
+/// `text`, with bounded fields free of control characters. Blank optional fields are dropped.
+pub fn link_previews(
+    previews: Vec<LinkPreview>,
+    text: &str,
+    max_count: u32,
+) -> Result<Vec<LinkPreview>, CurbError> {
+    if previews.len() > max_count as usize {
+        return Err(CurbError::TooManyLinkPreviews);
+    }
+    let bounded = |field: &str, max_bytes: usize| {
+        field.len() <= max_bytes && !field.chars().any(char::is_control)
+    };
+    let mut seen: Vec<&str> = Vec::new();
+    for preview in previews.iter() {
+        let url = preview.url.as_str();
+        let valid = (url.starts_with("https://") || url.starts_with("http://"))
+            && bounded(url, MAX_URL_BYTES)
+            && !url.contains(char::is_whitespace)
+            && contains_link(text, url)
+            && !seen.contains(&url);
+        if !valid {
+            return Err(CurbError::InvalidLinkPreview);
+        }
+        seen.push(url);
+    }
+    previews
+        .into_iter()
+        .map(|preview| {
+            let text_field = |field: Option<String>, max_bytes: usize| match field {
+                Some(field) => {
+                    let field = normalize(&field);
+                    if !bounded(&field, max_bytes) {
+                        return Err(CurbError::InvalidLinkPreview);
+                    }
+                    Ok((!field.trim().is_empty()).then_some(field))
+                }
+                None => Ok(None),
+            };
+            let image_uri = match preview.image_uri {
+                Some(uri) if uri.trim().is_empty() => None,
+                Some(uri) => {
+                    if !(uri.starts_with("https://") || uri.starts_with("ipfs://"))
+                        || !bounded(&uri, MAX_URL_BYTES)
+                        || uri.contains(char::is_whitespace)
+                    {
+                        return Err(CurbError::InvalidLinkPreview);
+                    }
+                    Some(uri)
+                }
+                None => None,
+            };
+            Ok(LinkPreview {
+                url: preview.url,
+                title: text_field(preview.title, MAX_PREVIEW_TITLE_BYTES)?,
+                description: text_field(preview.description, MAX_PREVIEW_DESCRIPTION_BYTES)?,
+                site_name: text_field(preview.site_name, MAX_PREVIEW_TITLE_BYTES)?,
+                image_uri,
+            })
+        })
+        .collect()
+}
+
+/// Normalizes a poll and checks its question and 2 to `max_options` distinct options are not
+/// blank and bounded in size, and that its deadline, if any, is after `now`.
+pub fn poll(poll: Poll, max_options: u32, now: u64) -> Result<Poll, CurbError> {
+    let text = |text: &str, max_bytes: usize| {
+        let text = normalize(text);
+        if text.trim().is_empty() || text.len() > max_bytes {
+            return Err(CurbError::InvalidPoll);
+        }
+        Ok(text)
+    };
+    let question = text(&poll.question, MAX_POLL_QUESTION_BYTES)?;
+    let options = poll
+        .options
+        .iter()
+        .map(|option| text(option, MAX_POLL_OPTION_BYTES))
+        .collect::<Result<Vec<_>, _>>()?;
+    let distinct = options
+        .iter()
+        .enumerate()
+        .all(|(i, option)| !options[..i].contains(option));
+    if options.len() < 2
+        || options.len() > max_options as usize
+        || !distinct
+        || poll.deadline.is_some_and(|deadline| deadline <= now)
+    {
+        return Err(CurbError::InvalidPoll);
+    }
+    Ok(Poll {
+        question,
+        options,
+        ..poll
+    })
+}
+
+/// Sorts the options of a vote and checks they are distinct options of `poll`, at most one
+/// unless it is multiple choice.
+pub fn poll_vote(mut options: Vec<u8>, poll: &Poll) -> Result<Vec<u8>, CurbError> {
+    options.sort_unstable();
+    let distinct = options.windows(2).all(|pair| pair[0] != pair[1]);
+    let in_range = options
+        .iter()
+        .all(|option| (*option as usize) < poll.options.len());
+    if !distinct || !in_range || (!poll.multiple_choice && options.len() > 1) {
+        return Err(CurbError::InvalidPollOption);
+    }
+    Ok(options)
+}
+
+/// Normalizes a custom status text and checks it is not blank, a single line without control
+/// characters and bounded in size.
+pub fn status_text(text: &str) -> Result<String, CurbError> {
+    let text = normalize(text.trim());
+    if text.is_empty() || text.len() > MAX_STATUS_TEXT_BYTES || text.chars().any(char::is_control) {
+        return Err(CurbError::InvalidStatus);
+    }
+    Ok(text)
+}
+
+/// Extracts the distinct hashtags of a message text, lowercased and without the `#`, keeping
+/// the first `max_count`. A tag starts with a `#` at the start of the text or after a character
+/// that is not part of a word, so `a#b` and URL fragments are not tags, and is made of letters,
+/// digits and `_`. Tags longer than 32 characters are ignored.
+pub fn tags(text: &str, max_count: usize) -> Vec<String> {
+    let text = normalize(text);
+    let mut tags: Vec<String> = vec![];
+    let mut previous: Option<char> = None;
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let at_boundary = previous.is_none_or(|p| !is_tag_char(p) && p != '#' && p != '/');
+        previous = Some(c);
+        if c != '#' || !at_boundary {
+            continue;
+        }
+        let mut end = start + 1;
+        while let Some((i, c)) = chars.next_if(|(_, c)| is_tag_char(*c)) {
+            end = i + c.len_utf8();
+            previous = Some(c);
+        }
+        let tag = text[start + 1..end].to_lowercase();
+        if tag.is_empty() || tag.chars().count() > MAX_TAG_CHARS || tags.contains(&tag) {
+            continue;
+        }
+        if tags.len() == max_count {
+            break;
+        }
+        tags.push(tag);
+    }
+    tags
+}
+
+/// Normalizes a tag being looked up the way [`tags`] extracts it, with or without the `#`.
+pub fn tag(tag: &str) -> String {
+    normalize(tag.strip_prefix('#').unwrap_or(tag)).to_lowercase()
+}
+
+fn is_tag_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_name_is_normalized() {
+        assert_eq!(channel_name("cafe\u{301}", 64), Ok("caf\u{e9}".to_string()));
+    }
+
+    #[test]
+    fn channel_name_rejects_blank() {
+        assert_eq!(channel_name("", 64), Err(CurbError::GroupNameEmpty));
+        assert_eq!(channel_name(" \t ", 64), Err(CurbError::GroupNameEmpty));
+    }
+
+    #[test]
+    fn channel_name_rejects_control_characters() {
+        assert_eq!(
+            channel_name("general\n", 64),
+            Err(CurbError::GroupNameInvalidCharacters)
+        );
+        assert_eq!(
+            channel_name("gen\u{7}eral", 64),
+            Err(CurbError::GroupNameInvalidCharacters)
+        );
+    }
+
+    #[test]
+    fn channel_name_length_counts_characters() {
+        assert_eq!(channel_name("ünïcödé", 7), Ok("ünïcödé".to_string()));
+        assert_eq!(
+            channel_name("ünïcödé!", 7),
+            Err(CurbError::GroupNameTooLong)
+        );
+    }
+
+    #[test]
+    fn message_length_counts_normalized_bytes() {
+        // Decomposed "é" is three bytes, composed it is two.
+        assert_eq!(message("e\u{301}", 2), Ok("\u{e9}".to_string()));
+        assert_eq!(message("abc", 2), Err(CurbError::MessageTooLong));
+        assert_eq!(message("", 2), Ok(String::new()));
+    }
+
+    #[test]
+    fn reaction_rejects_empty() {
+        assert_eq!(reaction("", 1, &None), Err(CurbError::ReactionEmpty));
+    }
+
+    #[test]
+    fn reaction_counts_graphemes() {
+        let family = "\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}";
+        assert_eq!(reaction(family, 1, &None), Ok(family.to_string()));
+        assert!(reaction("\u{1f44d}\u{1f3fd}", 1, &None).is_ok());
+        assert_eq!(reaction("ab", 1, &None), Err(CurbError::ReactionTooLong));
+        assert_eq!(reaction("ab", 2, &None), Ok("ab".to_string()));
+    }
+
+    #[test]
+    fn reaction_rejects_oversized_grapheme() {
+        let zalgo = format!("a{}", "\u{301}".repeat(40));
+        assert_eq!(reaction(&zalgo, 1, &None), Err(CurbError::ReactionTooLong));
+    }
+
+    #[test]
+    fn reaction_respects_allowlist() {
+        let allowed = Some(vec!["\u{1f44d}".to_string(), "\u{e9}".to_string()]);
+        assert_eq!(
+            reaction("\u{1f44d}", 1, &allowed),
+            Ok("\u{1f44d}".to_string())
+        );
+        assert_eq!(reaction("e\u{301}", 1, &allowed), Ok("\u{e9}".to_string()));
+        assert_eq!(
+            reaction("\u{1f44e}", 1, &allowed),
+            Err(CurbError::ReactionNotAllowed)
+        );
+    }
+
+    fn attachment() -> Attachment {
+        Attachment {
+            content_hash: "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+                .to_string(),
+            mime_type: "image/png".to_string(),
+            size: 1024,
+            filename: "cat.png".to_string(),
+            uri: "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi".to_string(),
+            thumbnail_hash: None,
+        }
+    }
+
+    #[test]
+    fn attachments_are_limited_in_count_and_size() {
+        assert_eq!(
+            attachments(vec![attachment(); 2], 2, 1024),
+            Ok(vec![attachment(); 2])
+        );
+        assert_eq!(
+            attachments(vec![attachment(); 3], 2, 1024),
+            Err(CurbError::TooManyAttachments)
+        );
+        assert_eq!(
+            attachments(vec![attachment()], 2, 1023),
+            Err(CurbError::AttachmentTooLarge)
+        );
+    }
+
+    #[test]
+    fn attachment_filename_is_normalized() {
+        let mut decomposed = attachment();
+        decomposed.filename = "cafe\u{301}.png".to_string();
+        assert_eq!(
+            attachments(vec![decomposed], 1, 1024).unwrap()[0].filename,
+            "caf\u{e9}.png"
+        );
+    }
+
+    #[test]
+    fn attachment_rejects_malformed_fields() {
+        let cases: [fn(&mut Attachment); 8] = [
+            |a| a.mime_type = "png".to_string(),
+            |a| a.mime_type = "image/".to_string(),
+            |a| a.mime_type = "image/png; charset=x".to_string(),
+            |a| a.content_hash = String::new(),
+            |a| a.uri = "x".repeat(MAX_ATTACHMENT_FIELD_BYTES + 1),
+            |a| a.filename = "../cat.png".to_string(),
+            |a| a.filename = "cat\n.png".to_string(),
+            |a| a.thumbnail_hash = Some(" ".to_string()),
+        ];
+        for case in cases {
+            let mut invalid = attachment();
+            case(&mut invalid);
+            assert_eq!(
+                attachments(vec![invalid], 1, 1024),
+                Err(CurbError::InvalidAttachment)
+            );
+        }
+    }
+
+    #[test]
+    fn code_language_is_lower_cased_identifier() {
+        let code = |language: &str| MessageFormat::Code {
+            language: Some(language.to_string()),
+        };
+        assert_eq!(format(code("C++")), Ok(code("c++")));
+        assert_eq!(format(MessageFormat::Markdown), Ok(MessageFormat::Markdown));
+        assert_eq!(
+            format(MessageFormat::Code { language: None }),
+            Ok(MessageFormat::Code { language: None })
+        );
+        for invalid in ["", "rust lang", "<script>", &"x".repeat(33)] {
+            assert_eq!(format(code(invalid)), Err(CurbError::InvalidCodeLanguage));
+        }
+    }
+
+    fn link(url: &str) -> LinkPreview {
+        LinkPreview {
+            url: url.to_string(),
+            title: Some("Title".to_string()),
+            description: None,
+            site_name: None,
+            image_uri: None,
+        }
+    }
+
+    #[test]
+    fn link_previews_must_match_links_in_text() {
+        let text = "see https://near.org and http://example.com/a";
+        let previews = vec![link("https://near.org"), link("http://example.com/a")];
+        assert_eq!(link_previews(previews.clone(), text, 2), Ok(previews));
+        assert_eq!(
+            link_previews(vec![link("https://near.org"); 3], text, 2),
+            Err(CurbError::TooManyLinkPreviews)
+        );
+        for invalid in [
+            vec![link("https://calimero.network")],
+            vec![link("ftp://near.org")],
+            vec![link("https://near.org"), link("https://near.org")],
+            vec![link("http://example.com")],
+        ] {
+            assert_eq!(
+                link_previews(invalid, text, 2),
+                Err(CurbError::InvalidLinkPreview)
+            );
+        }
+    }
+
+    #[test]
+    fn link_previews_match_whole_links() {
+        let text = "(https://near.org), <http://example.com/a>. https://x.org/b?c=1!";
+        for url in [
+            "https://near.org",
+            "http://example.com/a",
+            "https://x.org/b?c=1",
+        ] {
+            assert!(link_previews(vec![link(url)], text, 1).is_ok(), "{}", url);
+        }
+        let text = "https://near.org.evil.com https://near.org/path xhttps://near.org";
+        for invalid in [
+            vec![link("https://near.org")],
+            vec![link("https://near.org/pa")],
+        ] {
+            assert_eq!(
+                link_previews(invalid, text, 2),
+                Err(CurbError::InvalidLinkPreview)
+            );
+        }
+    }
+
+    #[test]
+    fn link_preview_fields_are_bounded() {
+        let text = "https://near.org";
+        let mut blank = link(text);
+        blank.title = Some(" ".to_string());
+        blank.image_uri = Some(String::new());
+        assert_eq!(link_previews(vec![blank], text, 1).unwrap()[0].title, None);
+
+        let mut long = link(text);
+        long.description = Some("x".repeat(MAX_PREVIEW_DESCRIPTION_BYTES + 1));
+        let mut image = link(text);
+        image.image_uri = Some("javascript:alert(1)".to_string());
+        for invalid in [long, image] {
+            assert_eq!(
+                link_previews(vec![invalid], text, 1),
+                Err(CurbError::InvalidLinkPreview)
+            );
+        }
+    }
+
+    fn question(options: &[&str]) -> Poll {
+        Poll {
+            question: "Lunch?".to_string(),
+            options: options.iter().map(|o| o.to_string()).collect(),
+            multiple_choice: false,
+            deadline: Some(2),
+        }
+    }
+
+    #[test]
+    fn poll_needs_distinct_options() {
+        assert_eq!(
+            poll(question(&["yes", "no"]), 2, 1),
+            Ok(question(&["yes", "no"]))
+        );
+        assert_eq!(
+            poll(question(&["caf\u{e9}", "cafe\u{301}"]), 2, 1),
+            Err(CurbError::InvalidPoll)
+        );
+        for options in [&["yes"][..], &["yes", "no", "maybe"], &["yes", " "]] {
+            assert_eq!(poll(question(options), 2, 1), Err(CurbError::InvalidPoll));
+        }
+    }
+
+    #[test]
+    fn poll_deadline_is_in_the_future() {
+        assert_eq!(
+            poll(question(&["yes", "no"]), 2, 2),
+            Err(CurbError::InvalidPoll)
+        );
+    }
+
+    #[test]
+    fn poll_vote_respects_choice_mode() {
+        let mut multiple = question(&["a", "b", "c"]);
+        assert_eq!(poll_vote(vec![1], &multiple), Ok(vec![1]));
+        assert_eq!(poll_vote(vec![], &multiple), Ok(vec![]));
+        assert_eq!(
+            poll_vote(vec![2, 0], &multiple),
+            Err(CurbError::InvalidPollOption)
+        );
+        multiple.multiple_choice = true;
+        assert_eq!(poll_vote(vec![2, 0], &multiple), Ok(vec![0, 2]));
+        for invalid in [vec![0, 0], vec![3]] {
+            assert_eq!(
+                poll_vote(invalid, &multiple),
+                Err(CurbError::InvalidPollOption)
+            );
+        }
+    }
+
+    #[test]
+    fn tags_are_extracted_at_word_boundaries() {
+        assert_eq!(
+            tags("#Release notes for #v2_0, see #release and a#b or #", 5),
+            ["release", "v2_0"]
+        );
+        assert_eq!(
+            tags("(#caf\u{e9}) https://example.com/#anchor ##double", 5),
+            ["caf\u{e9}"]
+        );
+        assert_eq!(tags("#a #b #c", 2), ["a", "b"]);
+        assert!(tags(&format!("#{}", "x".repeat(33)), 5).is_empty());
+    }
+
+    #[test]
+    fn tag_lookup_is_normalized() {
+        assert_eq!(tag("#Cafe\u{301}"), "caf\u{e9}");
+        assert_eq!(tag("rust"), "rust");
+    }
+
+    #[test]
+    fn status_text_is_a_bounded_line() {
+        assert_eq!(status_text(" lunch "), Ok("lunch".to_string()));
+        for invalid in [" ".to_string(), "a\nb".to_string(), "x".repeat(129)] {
+            assert_eq!(status_text(&invalid), Err(CurbError::InvalidStatus));
+        }
+    }
+}
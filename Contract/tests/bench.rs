@@ -0,0 +1,297 @@
+//! Gas and storage benchmarks of the hot paths across channel sizes, run in near-sandbox with
+//! `cargo test --test bench -- --ignored`.
+//!
+//! Results are written to `target/bench.json` and compared against `tests/bench_baseline.json`;
+//! the run fails when a measurement grows by more than `CURB_BENCH_THRESHOLD` (10% by default)
+//! or a call that used to fit in the gas limit no longer does. A measurement without a baseline
+//! entry fails too, so the baseline has to be recorded in the sandbox by running with
+//! `CURB_BENCH_UPDATE=1`, which writes the current results as the new baseline.
+
+mod common;
+
+use std::collections::BTreeMap;
+
+use common::Env;
+use curb::Config;
+use futures::future::try_join_all;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_units::parse_near;
+use serde_json::json;
+use workspaces::operations::Function;
+use workspaces::prelude::*;
+use workspaces::Account;
+
+const MEMBERS: [usize; 2] = [10, 1_000];
+const MESSAGES: [usize; 3] = [10, 1_000, 10_000];
+
+const BASELINE_PATH: &str = "tests/bench_baseline.json";
+const RESULTS_PATH: &str = "target/bench.json";
+const DEFAULT_THRESHOLD: f64 = 0.1;
+
+/// Accounts sending the seed messages concurrently, each from its own nonce.
+const SENDERS: usize = 10;
+const MESSAGES_PER_BATCH: usize = 20;
+const GAS_PER_MESSAGE: u64 = 12_000_000_000_000;
+/// Page size of the `get_messages` call clients make when opening a channel.
+const PAGE: usize = 50;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+struct Measurement {
+    /// Gas burnt, `None` when the call exceeded the gas limit.
+    gas: Option<u64>,
+    /// Bytes of contract storage the call added.
+    storage: Option<i64>,
+}
+
+type Results = BTreeMap<String, Measurement>;
+
+/// Whether a call failed for running out of gas. workspaces returns the `TxExecutionError` as is,
+/// which formats the function call error with `Debug`.
+fn gas_exceeded(error: &anyhow::Error) -> bool {
+    error.to_string().contains("HostError(GasExceeded)")
+}
+
+struct Bench<'a> {
+    env: &'a Env,
+    members: Vec<Account>,
+    sent: usize,
+}
+
+impl<'a> Bench<'a> {
+    /// Registers `members` accounts, `alice` first, with rate limits lifted so the channel can
+    /// be filled quickly.
+    async fn setup(env: &'a Env, members: usize) -> anyhow::Result<Bench<'a>> {
+        env.worker
+            .root_account()?
+            .transfer_near(&env.worker, env.contract.id(), parse_near!("1000 N"))
+            .await?;
+        env.ok(&env.alice, "join", json!({})).await?;
+        let mut config: Config = env.view("get_config", json!({})).await?;
+        config.rate_limits.messages_per_minute = 0;
+        config.rate_limits.reactions_per_minute = 0;
+        config.rate_limits.channels_per_day = 0;
+        env.ok(&env.alice, "set_config", json!({ "config": config }))
+            .await?;
+
+        let mut accounts = vec![env.alice.clone()];
+        while accounts.len() < members {
+            let chunk = (members - accounts.len()).min(50);
+            let joined = try_join_all((0..chunk).map(|_| async {
+                let account = env.worker.dev_create_account().await?;
+                env.ok(&account, "join", json!({})).await?;
+                anyhow::Ok(account)
+            }))
+            .await?;
+            accounts.extend(joined);
+        }
+        Ok(Bench {
+            env,
+            members: accounts,
+            sent: 0,
+        })
+    }
+
+    /// Sends messages to the default channel until it holds `total`.
+    async fn fill(&mut self, total: usize) -> anyhow::Result<()> {
+        let remaining = total.saturating_sub(self.sent);
+        let senders = self.members.len().min(SENDERS);
+        let (env, first) = (self.env, self.sent);
+        try_join_all(
+            self.members[..senders]
+                .iter()
+                .enumerate()
+                .map(|(i, sender)| {
+                    let timestamps: Vec<usize> =
+                        (first + i..first + remaining).step_by(senders).collect();
+                    async move {
+                        for batch in timestamps.chunks(MESSAGES_PER_BATCH) {
+                            let mut tx = sender.batch(&env.worker, env.contract.id());
+                            for timestamp in batch {
+                                tx = tx.call(
+                                    Function::new("send_message")
+                                        .args_json(json!({
+                                            "group": { "name": "general" },
+                                            "message": format!("message {}", timestamp),
+                                            "timestamp": timestamp,
+                                        }))?
+                                        .gas(GAS_PER_MESSAGE),
+                                );
+                            }
+                            tx.transact().await?;
+                        }
+                        anyhow::Ok(())
+                    }
+                }),
+        )
+        .await?;
+        self.sent += remaining;
+        Ok(())
+    }
+
+    async fn storage_usage(&self) -> anyhow::Result<i64> {
+        let details = self.env.contract.view_account(&self.env.worker).await?;
+        Ok(details.storage_usage as i64)
+    }
+
+    /// Calls `method` as the last member and records its gas and storage usage.
+    async fn measure(&self, method: &str, args: serde_json::Value) -> anyhow::Result<Measurement> {
+        let before = self.storage_usage().await?;
+        let caller = self.members.last().unwrap();
+        match self.env.call(caller, method, args).await {
+            Ok(details) => Ok(Measurement {
+                gas: Some(details.total_gas_burnt),
+                storage: Some(self.storage_usage().await? - before),
+            }),
+            Err(e) if gas_exceeded(&e) => {
+                eprintln!("{} failed: {}", method, e);
+                Ok(Measurement {
+                    gas: None,
+                    storage: None,
+                })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn run(&mut self, results: &mut Results) -> anyhow::Result<()> {
+        for messages in MESSAGES {
+            self.fill(messages).await?;
+            let scenario = format!("members={}/messages={}", self.members.len(), messages);
+
+            let page = json!({
+                "group": { "name": "general" },
+                "offset": self.sent.saturating_sub(PAGE),
+                "length": PAGE,
+            });
+            let reader = self.members.last().unwrap().id();
+            let measurements = [
+                ("get_messages", self.measure("get_messages", page).await?),
+                (
+                    "unread_messages",
+                    self.measure("unread_messages", json!({ "account": reader }))
+                        .await?,
+                ),
+                (
+                    "send_message",
+                    self.measure(
+                        "send_message",
+                        json!({
+                            "group": { "name": "general" },
+                            "message": "benchmark",
+                            "timestamp": self.sent,
+                        }),
+                    )
+                    .await?,
+                ),
+            ];
+            self.sent += 1;
+            for (method, measurement) in measurements {
+                results.insert(format!("{}/{}", method, scenario), measurement);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn exceeds(current: Option<i64>, baseline: Option<i64>, threshold: f64) -> bool {
+    match (current, baseline) {
+        (None, Some(_)) => true,
+        (Some(current), Some(baseline)) => {
+            current as f64 > baseline.max(0) as f64 * (1.0 + threshold)
+        }
+        _ => false,
+    }
+}
+
+fn regressions(results: &Results, baseline: &Results, threshold: f64) -> Vec<String> {
+    let mut regressions = Vec::new();
+    for (name, current) in results.iter() {
+        let base = match baseline.get(name) {
+            Some(base) => base,
+            None => {
+                regressions.push(format!(
+                    "{} has no baseline, record one with CURB_BENCH_UPDATE=1",
+                    name
+                ));
+                continue;
+            }
+        };
+        let gas = |m: &Measurement| m.gas.map(|gas| gas as i64);
+        if exceeds(gas(current), gas(base), threshold) {
+            regressions.push(format!("{} gas: {:?} -> {:?}", name, base.gas, current.gas));
+        }
+        if exceeds(current.storage, base.storage, threshold) {
+            regressions.push(format!(
+                "{} storage: {:?} -> {:?}",
+                name, base.storage, current.storage
+            ));
+        }
+    }
+    regressions
+}
+
+#[tokio::test]
+#[ignore = "requires near-sandbox, run with `cargo test --test bench -- --ignored`"]
+async fn gas_and_storage() -> anyhow::Result<()> {
+    let mut results = Results::new();
+    for members in MEMBERS {
+        let env = Env::new().await?;
+        Bench::setup(&env, members).await?.run(&mut results).await?;
+    }
+    std::fs::write(RESULTS_PATH, serde_json::to_string_pretty(&results)?)?;
+
+    if std::env::var_os("CURB_BENCH_UPDATE").is_some() {
+        std::fs::write(
+            BASELINE_PATH,
+            serde_json::to_string_pretty(&results)? + "\n",
+        )?;
+        return Ok(());
+    }
+    let baseline: Results = serde_json::from_str(&std::fs::read_to_string(BASELINE_PATH)?)?;
+    let threshold = match std::env::var("CURB_BENCH_THRESHOLD") {
+        Ok(threshold) => threshold.parse()?,
+        Err(_) => DEFAULT_THRESHOLD,
+    };
+    let regressions = regressions(&results, &baseline, threshold);
+    anyhow::ensure!(
+        regressions.is_empty(),
+        "regressions beyond {}%:\n{}",
+        threshold * 100.0,
+        regressions.join("\n")
+    );
+    Ok(())
+}
+
+#[test]
+fn regressions_respect_threshold() {
+    let measurement = |gas, storage| Measurement { gas, storage };
+    let baseline: Results = [
+        ("a".to_string(), measurement(Some(100), Some(100))),
+        ("b".to_string(), measurement(Some(100), Some(0))),
+        ("c".to_string(), measurement(Some(100), None)),
+    ]
+    .into_iter()
+    .collect();
+
+    let within: Results = [
+        ("a".to_string(), measurement(Some(110), Some(90))),
+        ("b".to_string(), measurement(Some(50), Some(0))),
+    ]
+    .into_iter()
+    .collect();
+    assert!(regressions(&within, &baseline, 0.1).is_empty());
+
+    let beyond: Results = [
+        ("a".to_string(), measurement(Some(111), Some(100))),
+        ("b".to_string(), measurement(Some(100), Some(1))),
+        ("c".to_string(), measurement(None, None)),
+        ("new".to_string(), measurement(Some(1), Some(1))),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(regressions(&beyond, &baseline, 0.1).len(), 4);
+
+    // An empty baseline does not pass every run.
+    assert_eq!(regressions(&within, &Results::new(), 0.1).len(), 2);
+}
@@ -0,0 +1,117 @@
+//! Sandbox environment shared by the sandbox and bench tests and the indexer's tests. Each of
+//! them uses only part of it, so helpers some do not use are allowed to be dead.
+
+use std::path::Path;
+
+use curb::CurbError;
+use curb_client::{CurbClient, Error, Finality};
+use near_crypto::InMemorySigner;
+use near_sdk::serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use workspaces::network::Sandbox;
+use workspaces::prelude::*;
+use workspaces::result::CallExecutionDetails;
+use workspaces::{Account, Contract, Worker};
+
+/// Release build produced by `build.sh`, relative to the workspace root.
+const WASM_PATH: &str = "target/wasm32-unknown-unknown/release/curb.wasm";
+
+/// A freshly deployed instance owned by `alice`, with `bob` and `carol` as further unregistered
+/// accounts.
+pub struct Env {
+    pub worker: Worker<Sandbox>,
+    pub contract: Contract,
+    pub alice: Account,
+    #[allow(dead_code)]
+    pub bob: Account,
+    #[allow(dead_code)]
+    pub carol: Account,
+}
+
+impl Env {
+    pub async fn new() -> anyhow::Result<Env> {
+        let worker = workspaces::sandbox().await?;
+        // Member crates sharing this module run their tests from their own directory.
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .ancestors()
+            .map(|dir| dir.join(WASM_PATH))
+            .find(|path| path.exists())
+            .ok_or_else(|| anyhow::anyhow!("{} not found, run ./build.sh first", WASM_PATH))?;
+        let wasm = std::fs::read(path)?;
+        let contract = worker.dev_deploy(&wasm).await?;
+        let alice = worker.dev_create_account().await?;
+        let bob = worker.dev_create_account().await?;
+        let carol = worker.dev_create_account().await?;
+        contract
+            .call(&worker, "new")
+            .args_json(json!({ "name": "Calimero", "owner": alice.id() }))?
+            .transact()
+            .await?;
+        Ok(Env {
+            worker,
+            contract,
+            alice,
+            bob,
+            carol,
+        })
+    }
+
+    /// Typed client signing as `account`, reading the latest state so it sees its own calls.
+    #[allow(dead_code)]
+    pub fn client(&self, account: &Account) -> anyhow::Result<CurbClient> {
+        let secret_key = serde_json::from_value(serde_json::to_value(account.secret_key())?)?;
+        let signer = InMemorySigner::from_secret_key(account.id().as_str().parse()?, secret_key);
+        let rpc_url = format!("http://localhost:{}", self.worker.rpc_port());
+        Ok(
+            CurbClient::new(&rpc_url, self.contract.id().as_str().parse()?)
+                .with_signer(signer)
+                .with_finality(Finality::None),
+        )
+    }
+
+    /// Calls `method` as `account`; a failed execution is returned as an error.
+    #[allow(dead_code)]
+    pub async fn call(
+        &self,
+        account: &Account,
+        method: &str,
+        args: Value,
+    ) -> anyhow::Result<CallExecutionDetails> {
+        account
+            .call(&self.worker, self.contract.id(), method)
+            .args_json(args)?
+            .max_gas()
+            .transact()
+            .await
+    }
+
+    #[allow(dead_code)]
+    pub async fn ok(&self, account: &Account, method: &str, args: Value) -> anyhow::Result<()> {
+        self.call(account, method, args).await?;
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    pub async fn view<T: DeserializeOwned>(&self, method: &str, args: Value) -> anyhow::Result<T> {
+        self.contract
+            .view(&self.worker, method, serde_json::to_vec(&args)?)
+            .await?
+            .json()
+    }
+}
+
+/// Fails unless `result` is the contract rejecting a call with `error`.
+#[allow(dead_code)]
+pub fn rejected<T>(result: curb_client::Result<T>, error: CurbError) -> anyhow::Result<()> {
+    match result {
+        Err(Error::Contract(info)) if info.code == error => Ok(()),
+        Err(e) => anyhow::bail!("failed with {} instead of {:?}", e, error),
+        Ok(_) => anyhow::bail!("succeeded instead of failing with {:?}", error),
+    }
+}
+
+/// The near-sdk id of a sandbox account.
+#[allow(dead_code)]
+pub fn id(account: &Account) -> near_sdk::AccountId {
+    account.id().as_str().parse().unwrap()
+}
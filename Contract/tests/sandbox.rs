@@ -0,0 +1,812 @@
+//! End-to-end tests against a local near-sandbox. They need the `near-sandbox` binary (set
+//! `NEAR_SANDBOX_BIN_PATH` or let `workspaces` download it) and are run with
+//! `cargo test -- --ignored`.
+
+mod common;
+
+use common::{id, rejected, Env};
+use curb::{
+    Attachment, Channel, CurbError, LinkPreview, MessageFormat, MessageOptions,
+    MessageWithReactionsAndThread, Poll, Presence, RetentionPolicy,
+};
+use curb_client::CurbClient;
+
+fn channel(name: &str) -> Channel {
+    Channel {
+        name: name.to_string(),
+    }
+}
+
+fn names(groups: Vec<Channel>) -> Vec<String> {
+    let mut names: Vec<String> = groups.into_iter().map(|g| g.name).collect();
+    names.sort();
+    names
+}
+
+async fn messages(
+    client: &CurbClient,
+    group: &str,
+) -> anyhow::Result<Vec<MessageWithReactionsAndThread>> {
+    Ok(client
+        .get_messages(None, Some(channel(group)), None, None, None)
+        .await?)
+}
+
+async fn chat(
+    client: &CurbClient,
+    accounts: (&workspaces::Account, &workspaces::Account),
+) -> anyhow::Result<Vec<MessageWithReactionsAndThread>> {
+    Ok(client
+        .get_messages(
+            Some((id(accounts.0), id(accounts.1))),
+            None,
+            None,
+            None,
+            None,
+        )
+        .await?)
+}
+
+async fn send(client: &CurbClient, group: &str, text: &str, timestamp: u64) -> anyhow::Result<()> {
+    client
+        .send_message(
+            None,
+            Some(channel(group)),
+            text.to_string(),
+            timestamp,
+            None,
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+async fn send_to(
+    client: &CurbClient,
+    account: &workspaces::Account,
+    text: &str,
+    timestamp: u64,
+) -> anyhow::Result<()> {
+    client
+        .send_message(
+            Some(id(account)),
+            None,
+            text.to_string(),
+            timestamp,
+            None,
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires near-sandbox, run with `cargo test -- --ignored`"]
+async fn join_registers_member_in_default_channel() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    let alice = env.client(&env.alice)?;
+    assert!(alice.join().await? == channel("general"));
+    rejected(alice.join().await, CurbError::AlreadyAMember)?;
+
+    let members = alice.get_members(None).await?;
+    assert_eq!(members.len(), 1);
+    assert_eq!(members[0].id, id(&env.alice));
+
+    let groups = alice.get_groups(Some(id(&env.alice))).await?;
+    assert_eq!(names(groups), vec!["general"]);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires near-sandbox, run with `cargo test -- --ignored`"]
+async fn channel_lifecycle() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    let (alice, bob) = (env.client(&env.alice)?, env.client(&env.bob)?);
+    rejected(
+        alice.create_group(channel("random")).await,
+        CurbError::NotAMember,
+    )?;
+    alice.join().await?;
+    bob.join().await?;
+    assert!(alice.create_group(channel("random")).await? == channel("random"));
+    rejected(
+        bob.create_group(channel("random")).await,
+        CurbError::GroupAlreadyExists,
+    )?;
+
+    bob.join_group(channel("random")).await?;
+    let members = bob.get_members(Some(channel("random"))).await?;
+    assert_eq!(members.len(), 2);
+
+    bob.leave_group(channel("random")).await?;
+    let groups = bob.get_groups(Some(id(&env.bob))).await?;
+    assert_eq!(names(groups), vec!["general"]);
+    assert_eq!(
+        names(bob.get_groups(None).await?),
+        vec!["general", "random"]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires near-sandbox, run with `cargo test -- --ignored`"]
+async fn leaving_last_member_deletes_channel() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    let alice = env.client(&env.alice)?;
+    alice.join().await?;
+    alice.create_group(channel("random")).await?;
+    send(&alice, "random", "hello", 1).await?;
+
+    alice.leave_group(channel("random")).await?;
+    assert_eq!(names(alice.get_groups(None).await?), vec!["general"]);
+    assert!(alice.channel_info(channel("random")).await?.is_none());
+
+    // The name is free again and the new channel starts empty.
+    alice.create_group(channel("random")).await?;
+    assert!(messages(&alice, "random").await?.is_empty());
+
+    // The default channel survives its last member leaving.
+    alice.leave_group(channel("general")).await?;
+    assert_eq!(
+        names(alice.get_groups(None).await?),
+        vec!["general", "random"]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires near-sandbox, run with `cargo test -- --ignored`"]
+async fn direct_messages() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    let (alice, bob) = (env.client(&env.alice)?, env.client(&env.bob)?);
+    alice.join().await?;
+    rejected(
+        alice
+            .send_message(Some(id(&env.bob)), None, "hi".to_string(), 1, None, None)
+            .await,
+        CurbError::OtherAccountNotAMember,
+    )?;
+    bob.join().await?;
+    send_to(&alice, &env.bob, "hi bob", 1).await?;
+    send_to(&bob, &env.alice, "hi alice", 2).await?;
+
+    // The chat is the same whichever way round the accounts are given.
+    for accounts in [(&env.alice, &env.bob), (&env.bob, &env.alice)] {
+        let chat = chat(&alice, accounts).await?;
+        let texts: Vec<&str> = chat.iter().map(|m| m.text.as_str()).collect();
+        assert_eq!(texts, vec!["hi bob", "hi alice"]);
+    }
+
+    // Sending marks the chat read up to the sent message.
+    let unread = alice.unread_messages(id(&env.alice)).await?;
+    assert_eq!(unread.chats[&id(&env.bob)].count, 1);
+    let unread = alice.unread_messages(id(&env.bob)).await?;
+    assert_eq!(unread.chats[&id(&env.alice)].count, 0);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires near-sandbox, run with `cargo test -- --ignored`"]
+async fn threads() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    let (alice, bob) = (env.client(&env.alice)?, env.client(&env.bob)?);
+    alice.join().await?;
+    bob.join().await?;
+    send(&alice, "general", "question", 1).await?;
+    let parent = messages(&alice, "general").await?.remove(0).id;
+
+    bob.send_message(
+        None,
+        Some(channel("general")),
+        "answer".to_string(),
+        2,
+        Some(parent.clone()),
+        None,
+    )
+    .await?;
+
+    // Replies live in the thread, not in the channel timeline.
+    let timeline = messages(&alice, "general").await?;
+    assert_eq!(timeline.len(), 1);
+    assert_eq!(timeline[0].thread.len(), 1);
+    assert_eq!(timeline[0].thread[0].text, "answer");
+
+    let unread = alice.unread_messages(id(&env.alice)).await?;
+    assert_eq!(unread.channels["general"].count, 0);
+    // Only threads an account replied in or read are followed.
+    assert!(!unread.threads.contains_key(&parent));
+    let unread = alice.unread_messages(id(&env.bob)).await?;
+    assert_eq!(unread.threads[&parent].count, 0);
+
+    alice
+        .send_message(
+            None,
+            Some(channel("general")),
+            "thanks".to_string(),
+            3,
+            Some(parent.clone()),
+            None,
+        )
+        .await?;
+    let unread = alice.unread_messages(id(&env.bob)).await?;
+    assert_eq!(unread.threads[&parent].count, 1);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires near-sandbox, run with `cargo test -- --ignored`"]
+async fn reactions_toggle() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    let (alice, bob) = (env.client(&env.alice)?, env.client(&env.bob)?);
+    alice.join().await?;
+    bob.join().await?;
+    send(&alice, "general", "hello", 1).await?;
+    let message = messages(&alice, "general").await?.remove(0).id;
+
+    let thumbs_up = "\u{1f44d}".to_string();
+    alice
+        .toggle_reaction(message.clone(), thumbs_up.clone())
+        .await?;
+    bob.toggle_reaction(message.clone(), thumbs_up.clone())
+        .await?;
+    let reactions = messages(&alice, "general").await?.remove(0).reactions;
+    assert_eq!(reactions.len(), 1);
+    assert_eq!(reactions[0].count, 2);
+
+    alice
+        .toggle_reaction(message.clone(), thumbs_up.clone())
+        .await?;
+    let viewed = alice
+        .get_messages(
+            None,
+            Some(channel("general")),
+            None,
+            None,
+            Some(id(&env.alice)),
+        )
+        .await?;
+    assert_eq!(viewed[0].reactions[0].count, 1);
+    assert!(!viewed[0].reactions[0].reacted_by_me);
+
+    let reactors = alice.get_reactors(message, thumbs_up, None, None).await?;
+    assert_eq!(reactors, vec![id(&env.bob)]);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires near-sandbox, run with `cargo test -- --ignored`"]
+async fn reactions_on_direct_messages_are_private() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    let (alice, bob, carol) = (
+        env.client(&env.alice)?,
+        env.client(&env.bob)?,
+        env.client(&env.carol)?,
+    );
+    for client in [&alice, &bob, &carol] {
+        client.join().await?;
+    }
+    send_to(&alice, &env.bob, "secret", 1).await?;
+    let message = chat(&alice, (&env.alice, &env.bob)).await?.remove(0).id;
+    let thumbs_up = "\u{1f44d}".to_string();
+
+    // Outsiders can not tell the message exists.
+    rejected(
+        carol
+            .toggle_reaction(message.clone(), thumbs_up.clone())
+            .await,
+        CurbError::MessageDoesNotExist,
+    )?;
+    bob.toggle_reaction(message, thumbs_up.clone()).await?;
+    rejected(
+        bob.toggle_reaction("missing".to_string(), thumbs_up).await,
+        CurbError::MessageDoesNotExist,
+    )?;
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires near-sandbox, run with `cargo test -- --ignored`"]
+async fn reactions_in_channels_require_membership() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    let (alice, bob, carol) = (
+        env.client(&env.alice)?,
+        env.client(&env.bob)?,
+        env.client(&env.carol)?,
+    );
+    alice.join().await?;
+    bob.join().await?;
+    alice.create_group(channel("random")).await?;
+    send(&alice, "random", "hello", 1).await?;
+    let message = messages(&alice, "random").await?.remove(0).id;
+    let thumbs_up = "\u{1f44d}".to_string();
+
+    rejected(
+        carol
+            .toggle_reaction(message.clone(), thumbs_up.clone())
+            .await,
+        CurbError::NotAMember,
+    )?;
+    rejected(
+        bob.toggle_reaction(message.clone(), thumbs_up.clone())
+            .await,
+        CurbError::NotAGroupMember,
+    )?;
+    bob.join_group(channel("random")).await?;
+    bob.toggle_reaction(message, thumbs_up).await?;
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires near-sandbox, run with `cargo test -- --ignored`"]
+async fn reactions_per_message_are_capped() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    let alice = env.client(&env.alice)?;
+    alice.join().await?;
+    let mut config = alice.get_config().await?;
+    config.max_reactions_per_message = 1;
+    alice.set_config(config).await?;
+    send(&alice, "general", "hello", 1).await?;
+    let message = messages(&alice, "general").await?.remove(0).id;
+    let (thumbs_up, party) = ("\u{1f44d}".to_string(), "\u{1f389}".to_string());
+
+    alice
+        .toggle_reaction(message.clone(), thumbs_up.clone())
+        .await?;
+    rejected(
+        alice.toggle_reaction(message.clone(), party.clone()).await,
+        CurbError::TooManyReactions,
+    )?;
+
+    // Removing the only reaction frees its slot.
+    alice.toggle_reaction(message.clone(), thumbs_up).await?;
+    alice.toggle_reaction(message, party).await?;
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires near-sandbox, run with `cargo test -- --ignored`"]
+async fn replies_and_forwards() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    let (alice, bob, carol) = (
+        env.client(&env.alice)?,
+        env.client(&env.bob)?,
+        env.client(&env.carol)?,
+    );
+    for client in [&alice, &bob, &carol] {
+        client.join().await?;
+    }
+    send(&alice, "general", "question", 1).await?;
+    let question = messages(&alice, "general").await?.remove(0).id;
+    send_to(&alice, &env.bob, "secret", 2).await?;
+    let secret = chat(&alice, (&env.alice, &env.bob)).await?.remove(0).id;
+
+    let reply_to = |id: &String| MessageOptions {
+        reply_to: Some(id.clone()),
+        ..Default::default()
+    };
+    bob.send_message(
+        None,
+        Some(channel("general")),
+        "answer".to_string(),
+        3,
+        None,
+        Some(reply_to(&question)),
+    )
+    .await?;
+    let timeline = messages(&alice, "general").await?;
+    let quote = timeline[1].reply_to.as_ref().unwrap();
+    assert_eq!((&quote.id, quote.text.as_str()), (&question, "question"));
+
+    // Replies stay in the conversation of the replied message.
+    rejected(
+        bob.send_message(
+            None,
+            Some(channel("general")),
+            "x".to_string(),
+            4,
+            None,
+            Some(reply_to(&secret)),
+        )
+        .await,
+        CurbError::InvalidReplyTarget,
+    )?;
+
+    // Messages can be forwarded anywhere, but only by accounts that can see them.
+    let forward = |id: &String| MessageOptions {
+        forwarded_from: Some(id.clone()),
+        ..Default::default()
+    };
+    rejected(
+        carol
+            .send_message(
+                None,
+                Some(channel("general")),
+                String::new(),
+                5,
+                None,
+                Some(forward(&secret)),
+            )
+            .await,
+        CurbError::MessageDoesNotExist,
+    )?;
+    bob.send_message(
+        Some(id(&env.carol)),
+        None,
+        String::new(),
+        6,
+        None,
+        Some(forward(&question)),
+    )
+    .await?;
+    let chat = chat(&bob, (&env.bob, &env.carol)).await?;
+    assert_eq!(chat[0].forwarded_from.as_ref().unwrap().id, question);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires near-sandbox, run with `cargo test -- --ignored`"]
+async fn attachments() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    let alice = env.client(&env.alice)?;
+    alice.join().await?;
+    let image = Attachment {
+        content_hash: "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+            .to_string(),
+        mime_type: "image/png".to_string(),
+        size: 1024,
+        filename: "cat.png".to_string(),
+        uri: "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi".to_string(),
+        thumbnail_hash: Some(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_string(),
+        ),
+    };
+    let send_attachments = |attachments: Vec<Attachment>, timestamp: u64| {
+        alice.send_message(
+            None,
+            Some(channel("general")),
+            String::new(),
+            timestamp,
+            None,
+            Some(MessageOptions {
+                attachments,
+                ..Default::default()
+            }),
+        )
+    };
+    send_attachments(vec![image.clone()], 1).await?;
+    let timeline = messages(&alice, "general").await?;
+    assert_eq!(timeline[0].attachments.len(), 1);
+    assert_eq!(timeline[0].attachments[0].filename, "cat.png");
+
+    let config = alice.get_config().await?;
+    rejected(
+        send_attachments(
+            vec![image.clone(); config.max_attachments_per_message as usize + 1],
+            2,
+        )
+        .await,
+        CurbError::TooManyAttachments,
+    )?;
+    let huge = Attachment {
+        size: config.max_attachment_size + 1,
+        ..image
+    };
+    rejected(
+        send_attachments(vec![huge], 3).await,
+        CurbError::AttachmentTooLarge,
+    )?;
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires near-sandbox, run with `cargo test -- --ignored`"]
+async fn formats_and_link_previews() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    let alice = env.client(&env.alice)?;
+    alice.join().await?;
+    let preview = LinkPreview {
+        url: "https://near.org".to_string(),
+        title: Some("NEAR".to_string()),
+        description: None,
+        site_name: None,
+        image_uri: None,
+    };
+    alice
+        .send_message(
+            None,
+            Some(channel("general")),
+            "fn main() {}".to_string(),
+            1,
+            None,
+            Some(MessageOptions {
+                format: MessageFormat::Code {
+                    language: Some("Rust".to_string()),
+                },
+                ..Default::default()
+            }),
+        )
+        .await?;
+    alice
+        .send_message(
+            None,
+            Some(channel("general")),
+            "**read** https://near.org".to_string(),
+            2,
+            None,
+            Some(MessageOptions {
+                format: MessageFormat::Markdown,
+                link_previews: vec![preview.clone()],
+                ..Default::default()
+            }),
+        )
+        .await?;
+    let timeline = messages(&alice, "general").await?;
+    assert_eq!(
+        timeline[0].format,
+        MessageFormat::Code {
+            language: Some("rust".to_string())
+        }
+    );
+    assert_eq!(timeline[1].format, MessageFormat::Markdown);
+    assert_eq!(timeline[1].link_previews[0].title.as_deref(), Some("NEAR"));
+
+    // Previews must describe links of the message.
+    rejected(
+        alice
+            .send_message(
+                None,
+                Some(channel("general")),
+                "no links here".to_string(),
+                3,
+                None,
+                Some(MessageOptions {
+                    link_previews: vec![preview],
+                    ..Default::default()
+                }),
+            )
+            .await,
+        CurbError::InvalidLinkPreview,
+    )?;
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires near-sandbox, run with `cargo test -- --ignored`"]
+async fn polls() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    let (alice, bob) = (env.client(&env.alice)?, env.client(&env.bob)?);
+    alice.join().await?;
+    bob.join().await?;
+    let poll = MessageOptions {
+        poll: Some(Poll {
+            question: "Lunch?".to_string(),
+            options: vec!["pizza".to_string(), "sushi".to_string()],
+            multiple_choice: false,
+            deadline: None,
+        }),
+        ..Default::default()
+    };
+    rejected(
+        alice
+            .send_message(
+                Some(id(&env.bob)),
+                None,
+                "Lunch?".to_string(),
+                1,
+                None,
+                Some(poll.clone()),
+            )
+            .await,
+        CurbError::InvalidPoll,
+    )?;
+    alice
+        .send_message(
+            None,
+            Some(channel("general")),
+            "Lunch?".to_string(),
+            1,
+            None,
+            Some(poll),
+        )
+        .await?;
+    send(&alice, "general", "plain", 2).await?;
+    let timeline = messages(&alice, "general").await?;
+    let (message, plain) = (timeline[0].id.clone(), timeline[1].id.clone());
+
+    alice.vote(message.clone(), vec![0]).await?;
+    bob.vote(message.clone(), vec![1]).await?;
+    bob.vote(message.clone(), vec![0]).await?;
+    rejected(
+        bob.vote(message.clone(), vec![0, 1]).await,
+        CurbError::InvalidPollOption,
+    )?;
+    rejected(bob.vote(plain, vec![0]).await, CurbError::NotAPoll)?;
+
+    let results = bob.get_poll(message, Some(id(&env.bob))).await?.unwrap();
+    assert_eq!((results.votes, results.voters), (vec![2, 0], 2));
+    assert_eq!(results.my_votes, vec![0]);
+    assert!(!results.closed);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires near-sandbox, run with `cargo test -- --ignored`"]
+async fn disappearing_direct_messages() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    let (alice, bob, carol) = (
+        env.client(&env.alice)?,
+        env.client(&env.bob)?,
+        env.client(&env.carol)?,
+    );
+    for client in [&alice, &bob, &carol] {
+        client.join().await?;
+    }
+    send_to(&alice, &env.bob, "hi", 1).await?;
+    rejected(
+        carol
+            .set_message_expiry(Some(id(&env.bob)), None, Some(1000))
+            .await,
+        CurbError::ChatDoesNotExist,
+    )?;
+    bob.set_message_expiry(Some(id(&env.alice)), None, Some(1000))
+        .await?;
+    send_to(&alice, &env.bob, "burn after reading", 2).await?;
+    let messages = chat(&alice, (&env.alice, &env.bob)).await?;
+    assert_eq!(messages.len(), 2);
+    assert!(messages[0].expires_at.is_none() && messages[1].expires_at.is_some());
+
+    // Blocks are about a second apart.
+    env.worker.fast_forward(10).await?;
+    assert_eq!(chat(&alice, (&env.alice, &env.bob)).await?.len(), 1);
+    let removed = carol
+        .remove_expired_messages(Some((id(&env.alice), id(&env.bob))), None, None)
+        .await?;
+    assert_eq!(removed, 1);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires near-sandbox, run with `cargo test -- --ignored`"]
+async fn retention_and_pruning() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    let (alice, bob) = (env.client(&env.alice)?, env.client(&env.bob)?);
+    alice.join().await?;
+    bob.join().await?;
+    for timestamp in 1..=5 {
+        send(&alice, "general", "hello", timestamp).await?;
+    }
+    let retention = RetentionPolicy {
+        max_messages: Some(2),
+        max_age_ms: None,
+    };
+    rejected(
+        bob.set_retention(channel("general"), retention.clone())
+            .await,
+        CurbError::NotAModerator,
+    )?;
+    alice.set_retention(channel("general"), retention).await?;
+
+    // Anyone can prune, in batches.
+    assert_eq!(bob.prune(channel("general"), Some(2)).await?, 2);
+    assert_eq!(bob.prune(channel("general"), Some(2)).await?, 1);
+    let timeline = messages(&alice, "general").await?;
+    assert_eq!(
+        timeline.iter().map(|m| m.timestamp).collect::<Vec<_>>(),
+        vec![4, 5]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires near-sandbox, run with `cargo test -- --ignored`"]
+async fn messages_by_tag() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    let alice = env.client(&env.alice)?;
+    alice.join().await?;
+    let mut config = alice.get_config().await?;
+    config.tag_index = true;
+    alice.set_config(config).await?;
+    send(&alice, "general", "shipping #release today", 1).await?;
+    send(&alice, "general", "no tags here", 2).await?;
+    send(&alice, "general", "#Release notes", 3).await?;
+
+    let tagged = alice
+        .get_messages_by_tag(channel("general"), "#release".to_string(), None, None, None)
+        .await?;
+    assert_eq!(
+        tagged
+            .messages
+            .iter()
+            .map(|m| m.timestamp)
+            .collect::<Vec<_>>(),
+        vec![3, 1]
+    );
+    assert!(tagged.next.is_none());
+    let newest = alice
+        .get_messages_by_tag(
+            channel("general"),
+            "release".to_string(),
+            None,
+            Some(1),
+            None,
+        )
+        .await?;
+    let older = alice
+        .get_messages_by_tag(
+            channel("general"),
+            "release".to_string(),
+            newest.next,
+            None,
+            None,
+        )
+        .await?;
+    assert_eq!(older.messages.len(), 1);
+    assert_eq!(older.messages[0].timestamp, 1);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires near-sandbox, run with `cargo test -- --ignored`"]
+async fn status_sets_presence() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    let alice = env.client(&env.alice)?;
+    rejected(
+        alice.set_status(Presence::Away, None, None).await,
+        CurbError::NotAMember,
+    )?;
+    alice.join().await?;
+    rejected(
+        alice
+            .set_status(Presence::Away, Some("line\nbreak".to_string()), None)
+            .await,
+        CurbError::InvalidStatus,
+    )?;
+    alice
+        .set_status(
+            Presence::DoNotDisturb,
+            Some("in a meeting".to_string()),
+            None,
+        )
+        .await?;
+
+    let members = alice.get_members(None).await?;
+    assert_eq!(members[0].presence, Presence::DoNotDisturb);
+    assert_eq!(members[0].status_text.as_deref(), Some("in a meeting"));
+    assert!(members[0].last_seen > 0);
+
+    alice.set_status(Presence::Online, None, None).await?;
+    let members = alice.get_members(None).await?;
+    assert_eq!(members[0].presence, Presence::Online);
+    assert_eq!(members[0].status_text, None);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires near-sandbox, run with `cargo test -- --ignored`"]
+async fn unread_counts_follow_read_marker() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    let (alice, bob) = (env.client(&env.alice)?, env.client(&env.bob)?);
+    alice.join().await?;
+    bob.join().await?;
+    for (timestamp, text) in ["one", "two", "three"].iter().enumerate() {
+        send(&alice, "general", text, timestamp as u64).await?;
+    }
+
+    let unread = bob.unread_messages(id(&env.bob)).await?;
+    assert_eq!(unread.channels["general"].count, 3);
+    assert_eq!(unread.channels["general"].last_seen, None);
+
+    let second = messages(&bob, "general").await?.remove(1).id;
+    bob.read_message(None, Some(channel("general")), second.clone())
+        .await?;
+    let unread = bob.unread_messages(id(&env.bob)).await?;
+    assert_eq!(unread.channels["general"].count, 1);
+    assert_eq!(unread.channels["general"].last_seen, Some(second));
+
+    // Only channels the account is a member of are reported.
+    alice.create_group(channel("random")).await?;
+    send(&alice, "random", "four", 4).await?;
+    let unread = bob.unread_messages(id(&env.bob)).await?;
+    assert!(!unread.channels.contains_key("random"));
+    Ok(())
+}